@@ -0,0 +1,175 @@
+//! The access log in Chrome's Trace Event Format, written with
+//! `--format chrome`, for loading into chrome://tracing or Perfetto.
+//!
+//! Every operation becomes a complete ("X") event named after it, on the
+//! track of the process that made it, with the path and result as arguments.
+//! The events are written as a JSON array while they come in; if swatch is
+//! killed before it can close the array, both viewers still load the file.
+
+use crate::jsonl::path_string;
+use crate::recorder::Event;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::UNIX_EPOCH;
+
+/// A complete event, covering one operation from start to end.
+#[derive(Serialize)]
+struct Complete {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    /// Microseconds, written out in full since a float would lose the
+    /// nanoseconds.
+    ts: Box<RawValue>,
+    dur: Box<RawValue>,
+    pid: u32,
+    tid: u32,
+    args: Args,
+}
+
+#[derive(Serialize)]
+struct Args {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    inode: u64,
+    errno: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// What has been written to a trace so far.
+pub struct Trace {
+    entries: usize,
+    /// Processes whose track has already been named.
+    named: HashSet<u32>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            entries: 0,
+            named: HashSet::new(),
+        }
+    }
+
+    pub fn write_header<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "[")
+    }
+
+    pub fn write_event<W: Write>(&mut self, out: &mut W, event: &Event) -> io::Result<()> {
+        if self.named.insert(event.pid) {
+            if let Some(name) = process_name(event.pid) {
+                let metadata = serde_json::json!({
+                    "name": "process_name",
+                    "ph": "M",
+                    "pid": event.pid,
+                    "args": { "name": name },
+                });
+                self.write_entry(out, &metadata)?;
+            }
+        }
+        let dur = event.end.duration_since(event.start).unwrap_or_default();
+        let complete = Complete {
+            name: event.op.name(),
+            cat: "fs",
+            ph: "X",
+            ts: micros(event.start.duration_since(UNIX_EPOCH).unwrap_or_default())?,
+            dur: micros(dur)?,
+            pid: event.pid,
+            tid: event.pid,
+            args: Args {
+                path: path_string(&event.path),
+                target: event.target.as_deref().map(path_string),
+                inode: event.ino,
+                errno: event.errno,
+                offset: event.offset,
+                size: event.size,
+            },
+        };
+        self.write_entry(out, &complete)
+    }
+
+    pub fn write_footer<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "]")
+    }
+
+    /// Writes an entry on a line of its own, after a comma if it isn't the
+    /// first. The comma leads rather than trails so each line is complete
+    /// once written.
+    fn write_entry<W: Write, T: Serialize>(&mut self, out: &mut W, entry: &T) -> io::Result<()> {
+        if self.entries > 0 {
+            write!(out, ",")?;
+        }
+        self.entries += 1;
+        serde_json::to_writer(&mut *out, entry)?;
+        writeln!(out)
+    }
+}
+
+/// The trace format's microseconds, keeping the nanoseconds as a fraction.
+fn micros(d: std::time::Duration) -> io::Result<Box<RawValue>> {
+    let nanos = d.as_nanos();
+    Ok(RawValue::from_string(format!(
+        "{}.{:03}",
+        nanos / 1000,
+        nanos % 1000
+    ))?)
+}
+
+/// What the process is called, where the platform says.
+fn process_name(pid: u32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::Op;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn complete_event() {
+        let start = UNIX_EPOCH + Duration::new(1_734_000_000, 123_456_789);
+        let event = Event {
+            op: Op::Rename,
+            path: PathBuf::from("a \"b\""),
+            target: Some(PathBuf::new()),
+            ino: 42,
+            pid: u32::MAX,
+            uid: 0,
+            gid: 0,
+            offset: None,
+            size: None,
+            errno: 0,
+            start,
+            end: start + Duration::from_nanos(1500),
+        };
+        let mut trace = Trace::new();
+        let mut out = Vec::new();
+        trace.write_header(&mut out).unwrap();
+        trace.write_event(&mut out, &event).unwrap();
+        trace.write_event(&mut out, &event).unwrap();
+        trace.write_footer(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        // Timestamps keep their nanoseconds
+        assert!(
+            text.contains(r#""ts":1734000000123456.789,"dur":1.500,"#),
+            "{}",
+            text
+        );
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["name"], "rename");
+        assert_eq!(entries[0]["args"]["path"], "a \"b\"");
+        assert_eq!(entries[0]["args"]["target"], ".");
+        assert!(entries[0]["args"].get("offset").is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! Project configuration, read from a `swatch.toml` like:
+//!
+//! ```toml
+//! ignore = [".git/", "*.swp"]
+//!
+//! [tasks.test]
+//! command = ["cargo", "test"]
+//! depfile = "target/test.d"
+//! depfile-target = "test"
+//! debounce = 250
+//! ignore = ["target/", "*.swp"]
+//! env = { RUST_BACKTRACE = "1" }
+//! ```
+//!
+//! The directory holding the file is SOURCE for every task in it, and paths in
+//! a task are relative to it. The patterns at the top apply to tasks, and to
+//! any SOURCE at or below that directory; like those given on the command
+//! line, they are matched relative to SOURCE.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = "swatch.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Patterns as for `--include`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Patterns as for `--ignore`.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    pub gitignore: bool,
+    #[serde(default)]
+    pub tasks: BTreeMap<String, Task>,
+}
+
+/// A command to run, with the flags it would otherwise need on the command line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Task {
+    pub command: Vec<String>,
+    pub depfile: Option<PathBuf>,
+    pub depfile_target: Option<String>,
+    /// Milliseconds, as for `--debounce`.
+    pub debounce: Option<u64>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Patterns as for `--include`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Patterns as for `--ignore`.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    pub gitignore: bool,
+}
+
+/// Finds the nearest `swatch.toml` in `start` or the directories above it,
+/// returning the directory it was found in along with what it says.
+pub fn discover(start: &Path) -> io::Result<Option<(PathBuf, Config)>> {
+    for dir in start.ancestors() {
+        let path = dir.join(FILE_NAME);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let config = parse(&text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        return Ok(Some((dir.to_owned(), config)));
+    }
+    Ok(None)
+}
+
+fn parse(text: &str) -> io::Result<Config> {
+    let config: Config =
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    for (name, task) in &config.tasks {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("task {}: {}", name, msg),
+            )
+        };
+        if task.command.is_empty() {
+            return Err(invalid("command is empty"));
+        }
+        // Mirrors the command line, where each flag requires the other
+        if task.depfile.is_some() != task.depfile_target.is_some() {
+            return Err(invalid("depfile and depfile-target must be given together"));
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("parsed: {}", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn valid_task() {
+        let config = parse(
+            r#"
+            ignore = ["*.swp"]
+
+            [tasks.test]
+            command = ["cargo", "test"]
+            depfile = "target/test.d"
+            depfile-target = "test"
+            debounce = 250
+            gitignore = true
+            env = { RUST_BACKTRACE = "1" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.ignore, ["*.swp"]);
+        assert!(config.include.is_empty());
+        let task = &config.tasks["test"];
+        assert_eq!(task.command, ["cargo", "test"]);
+        assert_eq!(task.depfile.as_deref(), Some(Path::new("target/test.d")));
+        assert_eq!(task.depfile_target.as_deref(), Some("test"));
+        assert_eq!(task.debounce, Some(250));
+        assert!(task.gitignore);
+        assert_eq!(task.env["RUST_BACKTRACE"], "1");
+        assert!(task.ignore.is_empty());
+    }
+
+    #[test]
+    fn empty_command() {
+        assert!(error("[tasks.x]\ncommand = []").contains("task x: command is empty"));
+        assert!(error("[tasks.x]\ndebounce = 1").contains("command"));
+    }
+
+    #[test]
+    fn half_a_depfile() {
+        let msg = "depfile and depfile-target must be given together";
+        assert!(error("[tasks.x]\ncommand = [\"true\"]\ndepfile = \"x.d\"").contains(msg));
+        assert!(error("[tasks.x]\ncommand = [\"true\"]\ndepfile-target = \"x\"").contains(msg));
+    }
+
+    #[test]
+    fn unknown_key() {
+        assert!(error("[tasks.x]\ncommand = [\"true\"]\ncommnd = [\"true\"]").contains("commnd"));
+        assert!(error("[task.x]\ncommand = [\"true\"]").contains("task"));
+    }
+
+    #[test]
+    fn discover_nearest() {
+        let dir = TempDir::new("swatch-config-test").unwrap();
+        let nested = dir.path().join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        let outer = dir.path().join(FILE_NAME);
+        let inner = dir.path().join("a").join(FILE_NAME);
+        std::fs::write(&outer, "[tasks.outer]\ncommand = [\"true\"]").unwrap();
+        std::fs::write(&inner, "[tasks.inner]\ncommand = [\"true\"]").unwrap();
+
+        let found = discover(&nested);
+        let dir_found = discover(dir.path());
+        std::fs::write(&inner, "[tasks.broken]").unwrap();
+        let broken = discover(&nested);
+        std::fs::remove_file(&inner).unwrap();
+        std::fs::remove_file(&outer).unwrap();
+        std::fs::remove_dir_all(dir.path().join("a")).unwrap();
+
+        let (at, config) = found.unwrap().unwrap();
+        assert_eq!(at, dir.path().join("a"));
+        assert!(config.tasks.contains_key("inner"));
+        let (at, config) = dir_found.unwrap().unwrap();
+        assert_eq!(at, dir.path());
+        assert!(config.tasks.contains_key("outer"));
+        // Errors name the file
+        let msg = broken.err().unwrap().to_string();
+        assert!(msg.starts_with(&inner.display().to_string()), "{}", msg);
+    }
+}
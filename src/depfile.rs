@@ -0,0 +1,90 @@
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Writes a Makefile-style dependency rule, `target: dep1 dep2 ...`, with
+/// each dependency on its own continuation line, then an empty rule for each
+/// dependency as `gcc -MP` does, so that Make doesn't stop with "No rule to
+/// make target" once one of them is deleted.
+///
+/// `missing` paths, which were looked for and not found, are listed the same
+/// way. While one is still missing, Make considers its empty rule to have
+/// remade it and Ninja considers it dirty, so the target is rebuilt on every
+/// run until it appears.
+pub fn write<'a, W, I, M>(out: &mut W, target: &str, deps: I, missing: M) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Path>,
+    M: IntoIterator<Item = &'a Path>,
+{
+    let deps: Vec<&Path> = deps.into_iter().chain(missing).collect();
+    write_escaped(out, target.as_bytes())?;
+    out.write_all(b":")?;
+    for dep in &deps {
+        out.write_all(b" \\\n  ")?;
+        write_escaped(out, dep.as_os_str().as_bytes())?;
+    }
+    out.write_all(b"\n")?;
+    for dep in &deps {
+        out.write_all(b"\n")?;
+        write_escaped(out, dep.as_os_str().as_bytes())?;
+        out.write_all(b":\n")?;
+    }
+    Ok(())
+}
+
+// Make and Ninja agree on backslash-escaping spaces and '#', and on doubling '$'
+fn write_escaped<W: Write>(out: &mut W, name: &[u8]) -> io::Result<()> {
+    for &b in name {
+        match b {
+            b' ' | b'#' => out.write_all(&[b'\\', b])?,
+            b'$' => out.write_all(b"$$")?,
+            _ => out.write_all(&[b])?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depfile(deps: &[&str], missing: &[&str]) -> String {
+        let mut out = Vec::new();
+        write(
+            &mut out,
+            "out",
+            deps.iter().map(Path::new),
+            missing.iter().map(Path::new),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn empty_rule_for_every_dependency() {
+        assert_eq!(
+            depfile(&["src/a.c", "src/a.h"], &["src/b.h"]),
+            "out: \\\n  src/a.c \\\n  src/a.h \\\n  src/b.h\n\nsrc/a.c:\n\nsrc/a.h:\n\nsrc/b.h:\n"
+        );
+        assert_eq!(depfile(&[], &[]), "out:\n");
+    }
+
+    #[test]
+    fn escaping() {
+        let escaped = |name: &str| {
+            let mut out = Vec::new();
+            write_escaped(&mut out, name.as_bytes()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(escaped("plain/path.c"), "plain/path.c");
+        assert_eq!(escaped("a file.c"), "a\\ file.c");
+        assert_eq!(escaped("#include"), "\\#include");
+        assert_eq!(escaped("$HOME/$$"), "$$HOME/$$$$");
+        assert_eq!(escaped("a b#c$"), "a\\ b\\#c$$");
+        assert_eq!(
+            depfile(&["dir with space/x"], &[]),
+            "out: \\\n  dir\\ with\\ space/x\n\ndir\\ with\\ space/x:\n"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Decides which paths under SOURCE are worth recording and watching, so that
+/// editor swap files, `.git/` and build directories don't show up in traces
+/// or trigger reruns.
+///
+/// Patterns use `.gitignore` syntax. A path is kept if it matches one of the
+/// include patterns, when there are any, and is not excluded by an ignore
+/// pattern or, optionally, by the `.gitignore` files in SOURCE.
+pub struct Filter {
+    /// What the patterns are rooted at; paths are joined onto it to match.
+    source: PathBuf,
+    include: Option<Gitignore>,
+    ignore: Gitignore,
+    /// Each `.gitignore` in SOURCE, by the directory it was found in.
+    gitignores: HashMap<PathBuf, Gitignore>,
+}
+
+impl Filter {
+    pub fn new(
+        source: &Path,
+        include: &[String],
+        ignore: &[String],
+        gitignore: bool,
+    ) -> io::Result<Filter> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(patterns(source, include)?)
+        };
+        let mut ignore = ignore.to_vec();
+        if gitignore {
+            // Git never tracks its own directory, so no .gitignore mentions it
+            ignore.push(".git".to_string());
+        }
+        let mut filter = Filter {
+            source: source.to_owned(),
+            include,
+            ignore: patterns(source, &ignore)?,
+            gitignores: HashMap::new(),
+        };
+        if gitignore {
+            filter.load_gitignores(Path::new(""))?;
+        }
+        Ok(filter)
+    }
+
+    /// Whether accesses to `path`, relative to SOURCE, should be kept. Paths
+    /// that would leave SOURCE never are.
+    pub fn includes(&self, path: &Path) -> bool {
+        if path.as_os_str().is_empty() {
+            return true;
+        }
+        // The matchers assert that what they are given is under SOURCE
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return false;
+        }
+        let full = self.source.join(path);
+        if let Some(include) = &self.include {
+            // Whether a path is a directory isn't known here, so `dir/`
+            // patterns are allowed to match anything by that name
+            if !include.matched_path_or_any_parents(&full, true).is_ignore() {
+                return false;
+            }
+        }
+        !self.ignored(path, &full)
+    }
+
+    fn ignored(&self, path: &Path, full: &Path) -> bool {
+        if self
+            .ignore
+            .matched_path_or_any_parents(full, true)
+            .is_ignore()
+        {
+            return true;
+        }
+        // The nearest .gitignore with something to say about the path wins
+        for dir in path.ancestors().skip(1) {
+            if let Some(gitignore) = self.gitignores.get(dir) {
+                match gitignore.matched_path_or_any_parents(full, true) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+
+    /// Reads the `.gitignore` files in `dir` and below, skipping directories
+    /// that are themselves ignored.
+    fn load_gitignores(&mut self, dir: &Path) -> io::Result<()> {
+        let full = self.source.join(dir);
+        let mut subdirs = Vec::new();
+        for entry in std::fs::read_dir(&full)? {
+            let entry = entry?;
+            if entry.file_name() == ".gitignore" {
+                let (gitignore, err) = Gitignore::new(entry.path());
+                if let Some(e) = err {
+                    log::warn!("{}: {}", entry.path().display(), e);
+                }
+                self.gitignores.insert(dir.to_owned(), gitignore);
+            } else if entry.file_type()?.is_dir() {
+                subdirs.push(dir.join(entry.file_name()));
+            }
+        }
+        for subdir in subdirs {
+            if !self.ignored(&subdir, &self.source.join(&subdir)) {
+                self.load_gitignores(&subdir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn patterns(source: &Path, lines: &[String]) -> io::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(source);
+    for line in lines {
+        builder
+            .add_line(None, line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    /// Files laid out in a temporary directory, removed again on drop.
+    struct Tree(TempDir);
+
+    impl Tree {
+        fn new(files: &[(&str, &str)]) -> Tree {
+            let dir = TempDir::new("swatch-filter-test").unwrap();
+            for (path, contents) in files {
+                let path = dir.path().join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+            Tree(dir)
+        }
+
+        fn path(&self) -> &Path {
+            self.0.path()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            for entry in std::fs::read_dir(self.path()).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path).unwrap();
+                } else {
+                    std::fs::remove_file(path).unwrap();
+                }
+            }
+        }
+    }
+
+    fn lines(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn includes(filter: &Filter, path: &str) -> bool {
+        filter.includes(Path::new(path))
+    }
+
+    #[test]
+    fn include_and_ignore() {
+        let tree = Tree::new(&[]);
+        let filter = Filter::new(
+            tree.path(),
+            &lines(&["src/", "Cargo.toml"]),
+            &lines(&["*.swp", "/src/generated"]),
+            false,
+        )
+        .unwrap();
+        assert!(includes(&filter, ""));
+        assert!(includes(&filter, "src"));
+        assert!(includes(&filter, "src/main.rs"));
+        assert!(includes(&filter, "Cargo.toml"));
+        assert!(!includes(&filter, "README.md"));
+        assert!(!includes(&filter, "src/.main.rs.swp"));
+        assert!(!includes(&filter, "src/generated/out.rs"));
+        // A leading slash anchors a pattern to SOURCE
+        assert!(includes(&filter, "src/nested/src/generated"));
+    }
+
+    #[test]
+    fn paths_outside_source() {
+        let tree = Tree::new(&[]);
+        let filter = Filter::new(tree.path(), &[], &lines(&["foo"]), false).unwrap();
+        assert!(!includes(&filter, "/etc/passwd"));
+        assert!(!includes(&filter, "../outside"));
+        assert!(!includes(&filter, "src/../../outside"));
+        assert!(includes(&filter, "src/main.rs"));
+    }
+
+    #[test]
+    fn nearest_gitignore_wins() {
+        let tree = Tree::new(&[
+            (".gitignore", "*.log\nbuild/\n"),
+            ("logs/.gitignore", "!keep.log\n"),
+            ("logs/deeper/.gitignore", "*.keep\n"),
+            // Never read, since its directory is ignored
+            ("build/.gitignore", "!*.log\n"),
+        ]);
+        let filter = Filter::new(tree.path(), &[], &[], true).unwrap();
+        assert!(!includes(&filter, "a.log"));
+        assert!(!includes(&filter, "logs/other.log"));
+        assert!(includes(&filter, "logs/keep.log"));
+        assert!(includes(&filter, "logs/deeper/keep.log"));
+        assert!(!includes(&filter, "logs/deeper/x.keep"));
+        assert!(includes(&filter, "x.keep"));
+        assert!(!includes(&filter, "build/a.log"));
+        assert!(!includes(&filter, ".git/HEAD"));
+        assert!(includes(&filter, "logs"));
+    }
+
+    #[test]
+    fn relative_source() {
+        // Tests run from the package root, which has target/ in its .gitignore
+        let filter = Filter::new(Path::new("."), &[], &lines(&["/src/main.rs"]), true).unwrap();
+        assert!(includes(&filter, "src/filter.rs"));
+        assert!(!includes(&filter, "src/main.rs"));
+        assert!(!includes(&filter, "target/debug/swatch"));
+    }
+}
@@ -0,0 +1,978 @@
+use chrono::DateTime;
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use libc::{EIO, ENOENT};
+use openat::{Dir, Metadata};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::inodes::{InodeTable, ROOT_INO};
+use crate::os::check;
+use crate::recorder::{Event, Op, Recorder};
+use crate::xattr;
+
+/// How long the kernel may cache entries and attributes by default.
+pub const TTL: Duration = Duration::from_secs(1); // 1 second
+
+struct DirEntry {
+    ino: u64,
+    kind: FileType,
+    name: OsString,
+}
+
+pub struct SwatchFS {
+    root: Dir,
+    // The absolute path of `root`, for the few calls that have no *at form
+    source: PathBuf,
+    writable: bool,
+    ttl: Duration,
+    inodes: InodeTable,
+    recorder: Arc<Mutex<Recorder>>,
+    // Directory listings are snapshotted at opendir so that the offsets
+    // handed out by readdir stay valid across paged calls.
+    dirs: HashMap<u64, Vec<DirEntry>>,
+    files: HashMap<u64, File>,
+    next_fh: u64,
+}
+
+impl SwatchFS {
+    pub fn new(
+        root: Dir,
+        source: PathBuf,
+        writable: bool,
+        ttl: Duration,
+        recorder: Arc<Mutex<Recorder>>,
+    ) -> SwatchFS {
+        SwatchFS {
+            root,
+            source,
+            writable,
+            ttl,
+            inodes: InodeTable::new(),
+            recorder,
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    /// Records `event` as finished now, with `errno` as its result.
+    fn record(&self, event: Event, errno: i32) {
+        let end = SystemTime::now();
+        self.recorder.lock().unwrap().add(Event {
+            errno,
+            end,
+            ..event
+        });
+    }
+
+    fn alloc_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    fn source_path(&self, path: &Path) -> PathBuf {
+        if path.as_os_str().is_empty() {
+            self.source.clone()
+        } else {
+            self.source.join(path)
+        }
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> Option<PathBuf> {
+        self.inodes.path(parent).map(|p| p.join(name))
+    }
+
+    /// Opens `path` with arbitrary open(2) flags, which `openat::Dir` only
+    /// offers a few fixed combinations of.
+    fn open_path(&self, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+        let path = c_path(path)?;
+        let flags = flags | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+        let fd = unsafe {
+            libc::openat(
+                self.root.as_raw_fd(),
+                path.as_ptr(),
+                flags,
+                mode as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Looks up a node that was just created at `path` and hands back its inode.
+    fn created(&mut self, path: PathBuf) -> io::Result<(u64, FileAttr)> {
+        let mut attr = self.attr(&path)?;
+        attr.ino = self.inodes.lookup(path);
+        Ok((attr.ino, attr))
+    }
+
+    /// Stats `path` for the kernel. The inode is left for the caller to fill
+    /// in, so that a new one is only allocated once this has succeeded.
+    fn attr(&self, path: &Path) -> io::Result<FileAttr> {
+        let meta = self.metadata(path)?;
+        let attr = meta_into_file_attr(0, &meta)
+            .inspect_err(|e| log::warn!("{}: {}", path.display(), e))?;
+        #[cfg(target_os = "linux")]
+        let attr = match self.birth_time(path) {
+            Some(crtime) => FileAttr { crtime, ..attr },
+            None => attr,
+        };
+        Ok(attr)
+    }
+
+    /// Linux's stat has no birth time, so ask statx, which has it on
+    /// filesystems that record one.
+    #[cfg(target_os = "linux")]
+    fn birth_time(&self, path: &Path) -> Option<SystemTime> {
+        let c = c_path(path).ok()?;
+        let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::statx(
+                self.root.as_raw_fd(),
+                c.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+                libc::STATX_BTIME,
+                &mut stx,
+            )
+        };
+        if res < 0 || stx.stx_mask & libc::STATX_BTIME == 0 {
+            return None;
+        }
+        let btime = stx.stx_btime;
+        timestamp_into_system_time(btime.tv_sec, btime.tv_nsec.into()).ok()
+    }
+
+    fn set_attrs(
+        &self,
+        path: &Path,
+        fh: Option<u64>,
+        mode: Option<u32>,
+        owner: (Option<u32>, Option<u32>),
+        size: Option<u64>,
+        times: (Option<TimeOrNow>, Option<TimeOrNow>),
+    ) -> io::Result<()> {
+        let fd = self.root.as_raw_fd();
+        let c = c_path(path)?;
+        if let Some(mode) = mode {
+            let mode = (mode & 0o7777) as libc::mode_t;
+            check(unsafe { libc::fchmodat(fd, c.as_ptr(), mode, 0) })?;
+        }
+        if owner.0.is_some() || owner.1.is_some() {
+            // -1 leaves that id unchanged
+            let uid = owner.0.unwrap_or(u32::MAX);
+            let gid = owner.1.unwrap_or(u32::MAX);
+            check(unsafe { libc::fchownat(fd, c.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+        }
+        if let Some(size) = size {
+            match fh.and_then(|fh| self.files.get(&fh)) {
+                Some(file) => file.set_len(size)?,
+                None => self.open_path(path, libc::O_WRONLY, 0)?.set_len(size)?,
+            }
+        }
+        if times.0.is_some() || times.1.is_some() {
+            let times = [time_into_timespec(times.0), time_into_timespec(times.1)];
+            check(unsafe {
+                libc::utimensat(fd, c.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        if path.as_os_str().is_empty() {
+            self.root.self_metadata()
+        } else {
+            self.root.metadata(path)
+        }
+    }
+
+    fn list_dir(&mut self, ino: u64, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let parent_ino = path
+            .parent()
+            .and_then(|p| self.inodes.find(p))
+            .unwrap_or(ROOT_INO);
+        let mut entries = vec![
+            DirEntry {
+                ino,
+                kind: FileType::Directory,
+                name: ".".into(),
+            },
+            DirEntry {
+                ino: parent_ino,
+                kind: FileType::Directory,
+                name: "..".into(),
+            },
+        ];
+        // list_self would share the root fd's offset, so open the root afresh
+        let dir = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        for entry in self.root.list_dir(dir)? {
+            let entry = entry?;
+            let child = path.join(entry.file_name());
+            // Entries can disappear between listing and stat; skip them
+            let Ok(meta) = self.root.metadata(&child) else {
+                continue;
+            };
+            let kind = match mode_into_file_type(meta.stat().st_mode) {
+                Ok(kind) => kind,
+                Err(e) => {
+                    log::warn!("{}: {}", child.display(), e);
+                    continue;
+                }
+            };
+            entries.push(DirEntry {
+                // The number stat will report once the kernel looks it up
+                ino: self.inodes.reserve(child),
+                kind,
+                name: entry.file_name().to_owned(),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// The root is the empty path, which the *at syscalls only accept as "."
+fn c_path(path: &Path) -> io::Result<CString> {
+    if path.as_os_str().is_empty() {
+        Ok(CString::new(".")?)
+    } else {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+}
+
+fn time_into_timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match time {
+        None => (0, libc::UTIME_OMIT),
+        Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+        Some(TimeOrNow::SpecificTime(t)) => match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as libc::time_t, d.subsec_nanos() as _),
+            Err(e) => {
+                // Before the epoch, with the nanoseconds counting forwards again
+                let d = e.duration();
+                let secs = -(d.as_secs() as libc::time_t);
+                match d.subsec_nanos() {
+                    0 => (secs, 0),
+                    n => (secs - 1, (1_000_000_000 - n) as _),
+                }
+            }
+        },
+    };
+    libc::timespec { tv_sec, tv_nsec }
+}
+
+/// Reads up to `size` bytes at `offset`, only stopping short at end of file.
+fn read_full_at(file: &File, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// The errno an operation that ended in `res` replies with, or 0 on success.
+fn errno<T>(res: &io::Result<T>) -> i32 {
+    res.as_ref().err().map_or(0, io_error_to_errno)
+}
+
+/// Gives the kernel the errno the underlying syscall failed with, so the
+/// command sees exactly what it would have without the mount. Errors raised
+/// by swatch itself get the closest errno to their kind.
+fn io_error_to_errno(e: &io::Error) -> i32 {
+    if let Some(errno) = e.raw_os_error() {
+        return errno;
+    }
+    match e.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => libc::EACCES,
+        ErrorKind::AlreadyExists => libc::EEXIST,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        ErrorKind::Unsupported => libc::ENOTSUP,
+        ErrorKind::OutOfMemory => libc::ENOMEM,
+        ErrorKind::Interrupted => libc::EINTR,
+        ErrorKind::WouldBlock => libc::EAGAIN,
+        _ => EIO,
+    }
+}
+
+fn mode_into_file_type(mode: libc::mode_t) -> io::Result<FileType> {
+    let typ = mode & libc::S_IFMT;
+    Ok(match typ {
+        libc::S_IFREG => FileType::RegularFile,
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown file type {:#o}", typ),
+            ))
+        }
+    })
+}
+
+fn timestamp_into_system_time(secs: i64, nsecs: i64) -> io::Result<SystemTime> {
+    let time = u32::try_from(nsecs)
+        .ok()
+        .and_then(|nsecs| DateTime::from_timestamp(secs, nsecs))
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("timestamp {}.{:09} out of range", secs, nsecs),
+            )
+        })?;
+    Ok(time.into())
+}
+
+fn meta_into_file_attr(ino: u64, m: &Metadata) -> io::Result<FileAttr> {
+    let s = m.stat();
+    let ctime = timestamp_into_system_time(s.st_ctime, s.st_ctime_nsec)?;
+    #[cfg(target_os = "macos")]
+    let (crtime, flags) = (
+        timestamp_into_system_time(s.st_birthtime, s.st_birthtime_nsec)?,
+        s.st_flags,
+    );
+    // Elsewhere there are no file flags, and birth time is left to the caller
+    #[cfg(not(target_os = "macos"))]
+    let (crtime, flags) = (ctime, 0);
+    Ok(FileAttr {
+        atime: timestamp_into_system_time(s.st_atime, s.st_atime_nsec)?,
+        mtime: timestamp_into_system_time(s.st_mtime, s.st_mtime_nsec)?,
+        ctime,
+        crtime,
+        ino,
+        blksize: s.st_blksize as u32,
+        size: s.st_size as u64,
+        blocks: s.st_blocks as u64,
+        flags,
+        gid: s.st_gid,
+        uid: s.st_uid,
+        nlink: s.st_nlink as u32,
+        perm: (s.st_mode & !libc::S_IFMT) as u16,
+        rdev: s.st_rdev as u32,
+        kind: mode_into_file_type(s.st_mode)?,
+    })
+}
+
+impl Filesystem for SwatchFS {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Lookup, 0, &path);
+        match self.attr(&path) {
+            Ok(mut attr) => {
+                attr.ino = self.inodes.lookup(path);
+                event.ino = attr.ino;
+                self.record(event, 0);
+                reply.entry(&self.ttl, &attr, 0);
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    event.op = Op::Missing;
+                }
+                let errno = io_error_to_errno(&e);
+                self.record(event, errno);
+                reply.error(errno);
+            }
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Getattr, ino, path);
+        let res = self.attr(path);
+        self.record(event, errno(&res));
+        match res {
+            Ok(attr) => reply.attr(&self.ttl, &FileAttr { ino, ..attr }),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Setattr, ino, path);
+        let res = self
+            .set_attrs(path, fh, mode, (uid, gid), size, (atime, mtime))
+            .and_then(|()| self.attr(path));
+        self.record(event, errno(&res));
+        match res {
+            Ok(attr) => reply.attr(&self.ttl, &FileAttr { ino, ..attr }),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Readlink, ino, path);
+        let res = self.root.read_link(path);
+        event.target = res.as_ref().ok().cloned();
+        self.record(event, errno(&res));
+        match res {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Mknod, 0, &path);
+        let res = c_path(&path).and_then(|c| {
+            let mode = (mode & !umask) as libc::mode_t;
+            check(unsafe {
+                libc::mknodat(self.root.as_raw_fd(), c.as_ptr(), mode, rdev as libc::dev_t)
+            })
+        });
+        let res = res.and_then(|()| self.created(path));
+        event.ino = res.as_ref().map_or(0, |&(ino, _)| ino);
+        self.record(event, errno(&res));
+        match res {
+            Ok((_, attr)) => reply.entry(&self.ttl, &attr, 0),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Mkdir, 0, &path);
+        let res = self
+            .root
+            .create_dir(&path, (mode & !umask) as libc::mode_t)
+            .and_then(|()| self.created(path));
+        event.ino = res.as_ref().map_or(0, |&(ino, _)| ino);
+        self.record(event, errno(&res));
+        match res {
+            Ok((_, attr)) => reply.entry(&self.ttl, &attr, 0),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let Some(path) = self.child_path(parent, link_name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Symlink, 0, &path);
+        event.target = Some(target.to_owned());
+        let res = self
+            .root
+            .symlink(&path, target)
+            .and_then(|()| self.created(path));
+        event.ino = res.as_ref().map_or(0, |&(ino, _)| ino);
+        self.record(event, errno(&res));
+        match res {
+            Ok((_, attr)) => reply.entry(&self.ttl, &attr, 0),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let ino = self.inodes.find(&path).unwrap_or(0);
+        let event = Event::start(req, Op::Unlink, ino, &path);
+        let res = self.root.remove_file(&path);
+        self.record(event, errno(&res));
+        match res {
+            Ok(()) => {
+                self.inodes.remove_path(&path);
+                reply.ok();
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let ino = self.inodes.find(&path).unwrap_or(0);
+        let event = Event::start(req, Op::Rmdir, ino, &path);
+        let res = self.root.remove_dir(&path);
+        self.record(event, errno(&res));
+        match res {
+            Ok(()) => {
+                self.inodes.remove_path(&path);
+                reply.ok();
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (Some(from), Some(to)) = (
+            self.child_path(parent, name),
+            self.child_path(newparent, newname),
+        ) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let ino = self.inodes.find(&from).unwrap_or(0);
+        let mut event = Event::start(req, Op::Rename, ino, &from);
+        event.target = Some(to.clone());
+        let res = if flags == 0 {
+            openat::rename(&self.root, &from, &self.root, &to)
+        } else {
+            rename_with_flags(&self.root, &from, &to, flags)
+        };
+        self.record(event, errno(&res));
+        match res {
+            Ok(()) => {
+                let exchange = flags & RENAME_EXCHANGE != 0;
+                self.inodes.rename(&from, &to, exchange);
+                reply.ok();
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if !self.writable && flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let op = if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            Op::Open
+        } else {
+            Op::OpenWrite
+        };
+        let event = Event::start(req, op, ino, path);
+        let res = self.open_path(path, flags, 0);
+        self.record(event, errno(&res));
+        match res {
+            Ok(file) => {
+                let fh = self.alloc_fh();
+                self.files.insert(fh, file);
+                reply.opened(fh, 0);
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(file) = self.files.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let event = self.inodes.path(ino).map(|path| Event {
+            offset: Some(offset),
+            size: Some(size.into()),
+            ..Event::start(req, Op::Read, ino, path)
+        });
+        let res = read_full_at(file, offset as u64, size as usize);
+        if let Some(event) = event {
+            self.record(event, errno(&res));
+        }
+        match res {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let Some(file) = self.files.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let event = self.inodes.path(ino).map(|path| Event {
+            offset: Some(offset),
+            size: Some(data.len() as u64),
+            ..Event::start(req, Op::Write, ino, path)
+        });
+        let res = file.write_all_at(data, offset as u64);
+        if let Some(event) = event {
+            self.record(event, errno(&res));
+        }
+        match res {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.files.remove(&fh);
+        reply.ok();
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let Some(path) = self.inodes.path(ino).map(Path::to_owned) else {
+            reply.error(ENOENT);
+            return;
+        };
+        match self.list_dir(ino, &path) {
+            Ok(entries) => {
+                let fh = self.alloc_fh();
+                self.dirs.insert(fh, entries);
+                reply.opened(fh, 0);
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(entries) = self.dirs.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        if let Some(path) = self.inodes.path(ino) {
+            let event = Event {
+                offset: Some(offset),
+                ..Event::start(req, Op::Readdir, ino, path)
+            };
+            self.record(event, 0);
+        }
+
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            if reply.add(entry.ino, (i + 1) as i64, entry.kind, &entry.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.dirs.remove(&fh);
+        reply.ok();
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Setxattr, ino, path);
+        let res = xattr::set(&self.source_path(path), name, value, flags);
+        self.record(event, errno(&res));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Getxattr, ino, path);
+        let mut buf = vec![0; size as usize];
+        let res = xattr::get(&self.source_path(path), name, &mut buf);
+        self.record(event, errno(&res));
+        match res {
+            Ok(len) if size == 0 => reply.size(len as u32),
+            Ok(len) => reply.data(&buf[..len]),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Listxattr, ino, path);
+        let mut buf = vec![0; size as usize];
+        let res = xattr::list(&self.source_path(path), &mut buf);
+        self.record(event, errno(&res));
+        match res {
+            Ok(len) if size == 0 => reply.size(len as u32),
+            Ok(len) => reply.data(&buf[..len]),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let event = Event::start(req, Op::Removexattr, ino, path);
+        let res = xattr::remove(&self.source_path(path), name);
+        self.record(event, errno(&res));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        // Everything under the mount lives on the filesystem SOURCE is on
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if let Err(e) = check(unsafe { libc::fstatvfs(self.root.as_raw_fd(), &mut st) }) {
+            reply.error(io_error_to_errno(&e));
+            return;
+        }
+        reply.statfs(
+            st.f_blocks as u64,
+            st.f_bfree as u64,
+            st.f_bavail as u64,
+            st.f_files as u64,
+            st.f_ffree as u64,
+            st.f_bsize as u32,
+            st.f_namemax as u32,
+            st.f_frsize as u32,
+        );
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let Some(path) = self.child_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let mut event = Event::start(req, Op::Create, 0, &path);
+        let res = self
+            .open_path(&path, flags | libc::O_CREAT, mode & !umask)
+            .and_then(|file| Ok((file, self.created(path)?)));
+        event.ino = res.as_ref().map_or(0, |&(_, (ino, _))| ino);
+        self.record(event, errno(&res));
+        match res {
+            Ok((file, (_, attr))) => {
+                let fh = self.alloc_fh();
+                self.files.insert(fh, file);
+                reply.created(&self.ttl, &attr, 0, fh, 0);
+            }
+            Err(e) => reply.error(io_error_to_errno(&e)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+#[cfg(not(target_os = "linux"))]
+const RENAME_EXCHANGE: u32 = 0;
+
+/// Renames with RENAME_NOREPLACE or RENAME_EXCHANGE, which `openat` has no
+/// public API for.
+#[cfg(target_os = "linux")]
+fn rename_with_flags(root: &Dir, from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    let fd = root.as_raw_fd();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            fd,
+            from.as_ptr(),
+            fd,
+            to.as_ptr(),
+            flags,
+        )
+    };
+    check(res as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_with_flags(_root: &Dir, _from: &Path, _to: &Path, _flags: u32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timespec(time: Option<TimeOrNow>) -> (libc::time_t, libc::c_long) {
+        let ts = time_into_timespec(time);
+        (ts.tv_sec, ts.tv_nsec)
+    }
+
+    #[test]
+    fn timestamps_keep_nanoseconds() {
+        let cases = [
+            (UNIX_EPOCH, (0, 0)),
+            (UNIX_EPOCH + Duration::new(1, 500_000_000), (1, 500_000_000)),
+            (
+                UNIX_EPOCH + Duration::new(1_734_000_000, 1),
+                (1_734_000_000, 1),
+            ),
+            // Before 1970 the seconds round down and the nanoseconds count up
+            (
+                UNIX_EPOCH - Duration::new(1, 500_000_000),
+                (-2, 500_000_000),
+            ),
+            (UNIX_EPOCH - Duration::new(1, 0), (-1, 0)),
+            (UNIX_EPOCH - Duration::new(0, 1), (-1, 999_999_999)),
+        ];
+        for (time, (secs, nsecs)) in cases {
+            assert_eq!(timespec(Some(TimeOrNow::SpecificTime(time))), (secs, nsecs));
+            assert_eq!(
+                timestamp_into_system_time(secs, nsecs).unwrap(),
+                time,
+                "{}.{:09}",
+                secs,
+                nsecs
+            );
+        }
+    }
+
+    #[test]
+    fn timespec_omit_and_now() {
+        assert_eq!(timespec(None), (0, libc::UTIME_OMIT));
+        assert_eq!(timespec(Some(TimeOrNow::Now)), (0, libc::UTIME_NOW));
+    }
+
+    #[test]
+    fn timestamps_out_of_range() {
+        for (secs, nsecs) in [(i64::MAX, 0), (i64::MIN, 0), (0, -1), (0, i64::MAX)] {
+            let err = timestamp_into_system_time(secs, nsecs).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The inode number FUSE uses for the root of the mount.
+pub const ROOT_INO: u64 = 1;
+
+struct Inode {
+    path: PathBuf,
+    lookups: u64,
+}
+
+/// Maps FUSE inode numbers to paths relative to the source directory.
+///
+/// Every successful `lookup` bumps the kernel's reference count on an inode,
+/// and `forget` drops it again; an entry is removed once nothing refers to it
+/// so the table only holds what the kernel currently has cached, and what
+/// directory listings have numbered for it to look up later.
+pub struct InodeTable {
+    by_ino: HashMap<u64, Inode>,
+    by_path: HashMap<PathBuf, u64>,
+    next_ino: u64,
+}
+
+impl InodeTable {
+    pub fn new() -> InodeTable {
+        let mut by_ino = HashMap::new();
+        let mut by_path = HashMap::new();
+        // The root is never forgotten, so it starts with a reference the
+        // kernel will never give back.
+        by_ino.insert(
+            ROOT_INO,
+            Inode {
+                path: PathBuf::new(),
+                lookups: 1,
+            },
+        );
+        by_path.insert(PathBuf::new(), ROOT_INO);
+        InodeTable {
+            by_ino,
+            by_path,
+            next_ino: ROOT_INO + 1,
+        }
+    }
+
+    /// Returns the path of `ino` relative to the source directory.
+    pub fn path(&self, ino: u64) -> Option<&Path> {
+        self.by_ino.get(&ino).map(|i| i.path.as_path())
+    }
+
+    /// Returns the inode already assigned to `path`, if any.
+    pub fn find(&self, path: &Path) -> Option<u64> {
+        self.by_path.get(path).copied()
+    }
+
+    /// Returns the inode for `path`, allocating one if needed, and records
+    /// one more kernel reference to it.
+    pub fn lookup(&mut self, path: PathBuf) -> u64 {
+        let ino = self.reserve(path);
+        self.by_ino.get_mut(&ino).unwrap().lookups += 1;
+        ino
+    }
+
+    /// Returns the inode for `path`, allocating one if needed, without a
+    /// kernel reference, so that a listing shows the number `lookup` will give.
+    /// It stays until `path` is removed or replaced.
+    pub fn reserve(&mut self, path: PathBuf) -> u64 {
+        if let Some(&ino) = self.by_path.get(&path) {
+            return ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.by_path.insert(path.clone(), ino);
+        self.by_ino.insert(ino, Inode { path, lookups: 0 });
+        ino
+    }
+
+    /// Detaches `path` from its inode after it has been removed, so a new
+    /// file created there gets an inode of its own.
+    pub fn remove_path(&mut self, path: &Path) {
+        if let Some(ino) = self.by_path.remove(path) {
+            self.drop_unreferenced(ino);
+        }
+    }
+
+    /// Moves every inode at or below `from` to the same place under `to`.
+    /// With `exchange`, anything under `to` moves to `from` at the same time.
+    pub fn rename(&mut self, from: &Path, to: &Path, exchange: bool) {
+        let moves: Vec<(u64, PathBuf)> = self
+            .by_ino
+            .iter()
+            .filter_map(|(&ino, inode)| {
+                if let Ok(rest) = inode.path.strip_prefix(from) {
+                    Some((ino, rebase(to, rest)))
+                } else if exchange {
+                    let rest = inode.path.strip_prefix(to).ok()?;
+                    Some((ino, rebase(from, rest)))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if !exchange {
+            // Whatever was at `to` has been replaced
+            if let Some(ino) = self.by_path.remove(to) {
+                self.drop_unreferenced(ino);
+            }
+        }
+        for (ino, _) in &moves {
+            let old = &self.by_ino[ino].path;
+            if self.by_path.get(old) == Some(ino) {
+                self.by_path.remove(old);
+            }
+        }
+        for (ino, new) in moves {
+            self.by_path.insert(new.clone(), ino);
+            self.by_ino.get_mut(&ino).unwrap().path = new;
+        }
+    }
+
+    /// Drops `nlookup` kernel references to `ino`, removing it once none remain.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == ROOT_INO {
+            return;
+        }
+        let Some(inode) = self.by_ino.get_mut(&ino) else {
+            return;
+        };
+        inode.lookups = inode.lookups.saturating_sub(nlookup);
+        if inode.lookups == 0 {
+            let inode = self.by_ino.remove(&ino).unwrap();
+            if self.by_path.get(&inode.path) == Some(&ino) {
+                self.by_path.remove(&inode.path);
+            }
+        }
+    }
+
+    /// Removes an inode that has lost its path, unless the kernel still holds it.
+    fn drop_unreferenced(&mut self, ino: u64) {
+        if ino != ROOT_INO && self.by_ino.get(&ino).is_some_and(|i| i.lookups == 0) {
+            self.by_ino.remove(&ino);
+        }
+    }
+}
+
+fn rebase(base: &Path, rest: &Path) -> PathBuf {
+    // Joining an empty path would add a trailing slash
+    if rest.as_os_str().is_empty() {
+        base.to_owned()
+    } else {
+        base.join(rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(table: &InodeTable, ino: u64) -> &str {
+        table.path(ino).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn rename_moves_cached_children() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup("dir".into());
+        let file = table.lookup("dir/sub/file".into());
+        let sibling = table.lookup("dirty".into());
+
+        table.rename(Path::new("dir"), Path::new("new/dir"), false);
+
+        assert_eq!(path(&table, dir), "new/dir");
+        assert_eq!(path(&table, file), "new/dir/sub/file");
+        assert_eq!(table.find(Path::new("new/dir/sub/file")), Some(file));
+        assert_eq!(table.find(Path::new("dir")), None);
+        assert_eq!(table.find(Path::new("dir/sub/file")), None);
+        // Only whole components count as being below `from`
+        assert_eq!(path(&table, sibling), "dirty");
+    }
+
+    #[test]
+    fn rename_replaces_target() {
+        let mut table = InodeTable::new();
+        let a = table.lookup("a".into());
+        let b = table.lookup("b".into());
+
+        table.rename(Path::new("a"), Path::new("b"), false);
+
+        assert_eq!(table.find(Path::new("b")), Some(a));
+        assert_eq!(table.find(Path::new("a")), None);
+        // The replaced inode is gone from the paths, though the kernel may
+        // still hold it
+        table.forget(b, 1);
+        assert_eq!(table.find(Path::new("b")), Some(a));
+        assert_eq!(table.path(b), None);
+    }
+
+    #[test]
+    fn rename_exchange_swaps_both_sides() {
+        let mut table = InodeTable::new();
+        let a = table.lookup("a".into());
+        let a_child = table.lookup("a/x".into());
+        let b = table.lookup("b".into());
+        let b_child = table.lookup("b/y".into());
+
+        table.rename(Path::new("a"), Path::new("b"), true);
+
+        assert_eq!(path(&table, a), "b");
+        assert_eq!(path(&table, a_child), "b/x");
+        assert_eq!(path(&table, b), "a");
+        assert_eq!(path(&table, b_child), "a/y");
+        assert_eq!(table.find(Path::new("a")), Some(b));
+        assert_eq!(table.find(Path::new("b")), Some(a));
+        assert_eq!(table.find(Path::new("a/x")), None);
+        assert_eq!(table.find(Path::new("b/y")), None);
+    }
+
+    #[test]
+    fn forget_after_path_reassigned() {
+        let mut table = InodeTable::new();
+        let old = table.lookup("file".into());
+        table.lookup("file".into());
+        table.remove_path(Path::new("file"));
+        let new = table.lookup("file".into());
+        assert_ne!(old, new);
+
+        table.forget(old, 1);
+        assert_eq!(path(&table, old), "file");
+        table.forget(old, 1);
+        assert_eq!(table.path(old), None);
+        // The new file keeps the path
+        assert_eq!(table.find(Path::new("file")), Some(new));
+        assert_eq!(path(&table, new), "file");
+
+        table.forget(new, 1);
+        assert_eq!(table.find(Path::new("file")), None);
+    }
+
+    #[test]
+    fn reserve_numbers_before_lookup() {
+        let mut table = InodeTable::new();
+        let listed = table.reserve("file".into());
+        assert_eq!(table.reserve("file".into()), listed);
+        assert_eq!(table.lookup("file".into()), listed);
+        table.forget(listed, 1);
+        assert_eq!(table.find(Path::new("file")), None);
+
+        // Reserved numbers go with their path, having no kernel reference
+        let gone = table.reserve("gone".into());
+        table.remove_path(Path::new("gone"));
+        assert_eq!(table.path(gone), None);
+        let replaced = table.reserve("replaced".into());
+        let moved = table.reserve("moved".into());
+        table.rename(Path::new("moved"), Path::new("replaced"), false);
+        assert_eq!(table.path(replaced), None);
+        assert_eq!(table.find(Path::new("replaced")), Some(moved));
+    }
+
+    #[test]
+    fn root_is_never_forgotten() {
+        let mut table = InodeTable::new();
+        table.forget(ROOT_INO, 100);
+        assert_eq!(table.path(ROOT_INO), Some(Path::new("")));
+        assert_eq!(table.find(Path::new("")), Some(ROOT_INO));
+    }
+}
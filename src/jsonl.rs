@@ -0,0 +1,235 @@
+//! The access log as JSON Lines, written with `--format jsonl`.
+//!
+//! The first line names the format and its version:
+//!
+//! ```json
+//! {"format":"swatch-trace","version":1}
+//! ```
+//!
+//! Every line after it is one operation served through the mount, with these
+//! keys in this order:
+//!
+//! - `op`: the operation, named as in the text log (`lookup`, `missing`,
+//!   `open`, `open-write`, `read`, `rename`, ...)
+//! - `path`: relative to SOURCE, or `.` for SOURCE itself
+//! - `target`: for a rename where `path` was moved to, for a symlink or
+//!   readlink what it points at; otherwise null
+//! - `inode`: the inode the kernel knows the path by, or 0 if it has none
+//! - `pid`, `uid`, `gid`: of the process that made the request
+//! - `offset`: for reads, writes and directory listings, where they started;
+//!   otherwise null
+//! - `size`: for reads and writes, how many bytes were asked for; otherwise null
+//! - `errno`: what the operation failed with, or 0 if it succeeded
+//! - `start`, `end`: when the operation was received and when it was
+//!   answered, as RFC 3339 UTC timestamps with nanoseconds
+//!
+//! Bytes in paths that aren't valid UTF-8 are replaced with U+FFFD.
+//!
+//! Within a version, keys may be added but are never removed, renamed or
+//! given a different meaning; anything else comes with a new version. Readers
+//! should ignore keys they don't know.
+
+use crate::recorder::{display_path, parse_path, parse_target, Event, Op};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+pub const VERSION: u32 = 1;
+
+const FORMAT: &str = "swatch-trace";
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+/// One line of the trace; the field order is the key order on the line.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    op: String,
+    path: String,
+    target: Option<String>,
+    inode: u64,
+    pid: u32,
+    uid: u32,
+    gid: u32,
+    offset: Option<i64>,
+    size: Option<u64>,
+    errno: i32,
+    start: String,
+    end: String,
+}
+
+pub fn write_header<W: Write>(out: &mut W) -> io::Result<()> {
+    let header = Header {
+        format: FORMAT.to_string(),
+        version: VERSION,
+    };
+    write_line(out, &header)
+}
+
+/// Whether `line` is the first line of a trace in this format, failing if it
+/// is one but of a version this can't read.
+pub fn read_header(line: &str) -> io::Result<bool> {
+    match serde_json::from_str::<Header>(line) {
+        Ok(header) if header.format == FORMAT => {
+            if header.version != VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "trace is version {}, but only version {} can be read",
+                        header.version, VERSION
+                    ),
+                ));
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+pub fn write_event<W: Write>(out: &mut W, event: &Event) -> io::Result<()> {
+    let record = Record {
+        op: event.op.name().to_string(),
+        path: path_string(&event.path),
+        target: event.target.as_deref().map(path_string),
+        inode: event.ino,
+        pid: event.pid,
+        uid: event.uid,
+        gid: event.gid,
+        offset: event.offset,
+        size: event.size,
+        errno: event.errno,
+        start: timestamp(event.start),
+        end: timestamp(event.end),
+    };
+    write_line(out, &record)
+}
+
+/// Reads back an event written by `write_event`.
+pub fn parse_event(line: &str) -> Option<Event> {
+    let record: Record = serde_json::from_str(line).ok()?;
+    let op = Op::from_name(&record.op)?;
+    let target = match record.target {
+        Some(target) => Some(parse_target(op, &target)?),
+        None => None,
+    };
+    Some(Event {
+        op,
+        path: parse_path(&record.path)?,
+        target,
+        ino: record.inode,
+        pid: record.pid,
+        uid: record.uid,
+        gid: record.gid,
+        offset: record.offset,
+        size: record.size,
+        errno: record.errno,
+        start: parse_timestamp(&record.start)?,
+        end: parse_timestamp(&record.end)?,
+    })
+}
+
+fn write_line<W: Write, T: Serialize>(out: &mut W, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+/// A path as both JSON formats write it.
+pub fn path_string(path: &Path) -> String {
+    display_path(path).to_string_lossy().into_owned()
+}
+
+fn timestamp(time: SystemTime) -> String {
+    let time: DateTime<Utc> = time.into();
+    time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    Some(DateTime::parse_from_rfc3339(s).ok()?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn event(op: Op, path: &str, target: Option<&str>) -> Event {
+        let start = SystemTime::UNIX_EPOCH + Duration::new(1_734_000_000, 123_456_789);
+        Event {
+            op,
+            path: PathBuf::from(path),
+            target: target.map(PathBuf::from),
+            ino: 42,
+            pid: 1000,
+            uid: 1001,
+            gid: 1002,
+            offset: Some(-1),
+            size: Some(4096),
+            errno: 0,
+            start,
+            end: start + Duration::from_nanos(1),
+        }
+    }
+
+    fn round_trip(event: &Event) -> Event {
+        let mut out = Vec::new();
+        write_event(&mut out, event).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert_eq!(line.matches('\n').count(), 1, "{}", line);
+        parse_event(line.trim_end()).unwrap_or_else(|| panic!("unparseable: {}", line))
+    }
+
+    #[test]
+    fn event_round_trip() {
+        for event in [
+            event(Op::Read, "src/main.rs", None),
+            event(Op::Lookup, "", None),
+            event(Op::Open, "quote \" backslash \\ newline \n tab \t", None),
+            event(Op::Rename, "old name", Some("dir/new name")),
+            event(Op::Rename, "dir", Some("")),
+            event(Op::Symlink, "link", Some("/etc/passwd")),
+        ] {
+            let parsed = round_trip(&event);
+            assert_eq!(parsed.op, event.op);
+            assert_eq!(parsed.path, event.path);
+            assert_eq!(parsed.target, event.target);
+            assert_eq!(
+                (parsed.ino, parsed.pid, parsed.uid, parsed.gid, parsed.errno),
+                (event.ino, event.pid, event.uid, event.gid, event.errno)
+            );
+            assert_eq!((parsed.offset, parsed.size), (event.offset, event.size));
+            assert_eq!((parsed.start, parsed.end), (event.start, event.end));
+        }
+    }
+
+    #[test]
+    fn keys_in_order() {
+        let mut out = Vec::new();
+        write_event(&mut out, &event(Op::Getattr, "", None)).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(
+            line.starts_with(r#"{"op":"getattr","path":".","target":null,"inode":42,"#),
+            "{}",
+            line
+        );
+        assert!(line.contains(r#""start":"2024-12-12T10:40:00.123456789Z""#));
+    }
+
+    #[test]
+    fn header() {
+        let mut out = Vec::new();
+        write_header(&mut out).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert_eq!(line, "{\"format\":\"swatch-trace\",\"version\":1}\n");
+        assert!(read_header(line.trim_end()).unwrap());
+        assert!(read_header(r#"{"format":"swatch-trace","version":2}"#).is_err());
+        assert!(
+            !read_header("2024-12-12T10:40:00Z read ino=1 pid=1 uid=0 gid=0 errno=0 a").unwrap()
+        );
+    }
+}
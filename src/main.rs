@@ -1,178 +1,590 @@
-use chrono::DateTime;
-use clap::{crate_version, Arg, ArgAction, Command};
-use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
-};
-use libc::ENOENT;
-use openat::{Dir, Metadata};
-use std::ffi::OsStr;
-use std::io::ErrorKind;
+use clap::{crate_authors, crate_version, Arg, ArgAction, ArgMatches, Command};
+use fuser::MountOption;
+use openat::Dir;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitCode, ExitStatus};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-const TTL: Duration = Duration::from_secs(1); // 1 second
-
-struct SwatchFS {
-    root: Dir,
-}
-
-fn meta_into_file_attr(m: &Metadata) -> FileAttr {
-    let s = m.stat();
-    let typ = s.st_mode & libc::S_IFMT;
-    FileAttr {
-        atime: DateTime::from_timestamp(s.st_atime, 0).unwrap().into(),
-        mtime: DateTime::from_timestamp(s.st_mtime, 0).unwrap().into(),
-        ctime: DateTime::from_timestamp(s.st_ctime, 0).unwrap().into(),
-        crtime: DateTime::from_timestamp(s.st_birthtime, 0).unwrap().into(),
-        ino: s.st_ino,
-        blksize: s.st_blksize as u32,
-        size: s.st_size as u64,
-        blocks: s.st_blocks as u64,
-        flags: s.st_flags,
-        gid: s.st_gid,
-        uid: s.st_uid,
-        nlink: s.st_nlink as u32,
-        perm: s.st_mode & !libc::S_IFMT,
-        rdev: s.st_rdev as u32,
-        kind: match typ {
-            libc::S_IFREG => FileType::RegularFile,
-            libc::S_IFDIR => FileType::Directory,
-            libc::S_IFLNK => FileType::Symlink,
-            libc::S_IFBLK => FileType::BlockDevice,
-            libc::S_IFCHR => FileType::CharDevice,
-            libc::S_IFIFO => FileType::NamedPipe,
-            libc::S_IFSOCK => FileType::Socket,
-            _ => panic!("unknown file type {:?}", typ),
+mod chrome;
+mod config;
+mod depfile;
+mod filter;
+mod fs;
+mod inodes;
+mod jsonl;
+mod namespace;
+mod os;
+mod recorder;
+mod signals;
+mod tempdir;
+mod watch;
+mod xattr;
+
+use config::{Config, Task};
+use filter::Filter;
+use fs::SwatchFS;
+use recorder::{Event, Format, Recorder};
+use tempdir::TempDir;
+use watch::Seen;
+
+/// Arguments for anything that mounts SOURCE.
+fn mount_args() -> Vec<Arg> {
+    vec![
+        Arg::new("SOURCE")
+            .required(true)
+            .index(1)
+            .help("Directory to monitor"),
+        Arg::new("MOUNT_POINT")
+            .index(2)
+            .help("Mount FUSE at given path, rather than at a temporary directory"),
+        Arg::new("allow-root")
+            .long("allow-root")
+            .action(ArgAction::SetTrue)
+            .help("Allow root user to access filesystem"),
+        Arg::new("allow-other")
+            .long("allow-other")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("allow-root")
+            .help("Allow all users to access filesystem, subject to the files' permissions"),
+        Arg::new("read-write")
+            .long("read-write")
+            .action(ArgAction::SetTrue)
+            .help("Let the command write to SOURCE through the mount"),
+        Arg::new("log")
+            .long("log")
+            .value_name("FILE")
+            .help("Write a line to FILE for every access made through the mount"),
+        Arg::new("format")
+            .long("format")
+            .value_name("FORMAT")
+            .value_parser(["text", "jsonl", "chrome"])
+            .default_value("text")
+            .requires("log")
+            .help("Write the --log FILE as plain text, JSON Lines, or a Chrome trace"),
+    ]
+}
+
+/// Arguments for leaving paths out of what is recorded and watched.
+fn filter_args() -> Vec<Arg> {
+    vec![
+        Arg::new("include")
+            .long("include")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Only record paths matching PATTERN, in .gitignore syntax"),
+        Arg::new("ignore")
+            .long("ignore")
+            .value_name("PATTERN")
+            .action(ArgAction::Append)
+            .help("Don't record or watch paths matching PATTERN, in .gitignore syntax"),
+        Arg::new("gitignore")
+            .long("gitignore")
+            .action(ArgAction::SetTrue)
+            .help("Also leave out paths ignored by the .gitignore files in SOURCE"),
+    ]
+}
+
+/// Arguments for writing out a depfile of what was read.
+fn depfile_args() -> Vec<Arg> {
+    vec![
+        Arg::new("depfile")
+            .long("depfile")
+            .value_name("FILE")
+            .requires("depfile-target")
+            .help("Write a Makefile-style depfile of the files that were read"),
+        Arg::new("depfile-target")
+            .long("depfile-target")
+            .value_name("TARGET")
+            .requires("depfile")
+            .help("The target named in the depfile"),
+        Arg::new("depfile-missing")
+            .long("depfile-missing")
+            .action(ArgAction::SetTrue)
+            .help(
+                "Also list paths that were looked for and not found in the depfile; \
+                 the target is then rebuilt every time until they appear",
+            ),
+    ]
+}
+
+/// Arguments for running a command on the mount.
+fn command_args() -> Vec<Arg> {
+    vec![
+        Arg::new("summary")
+            .long("summary")
+            .action(ArgAction::SetTrue)
+            .overrides_with("no-summary")
+            .help(
+                "Print each path the command used and whether it was an input or output \
+                 [default for run]",
+            ),
+        Arg::new("no-summary")
+            .long("no-summary")
+            .action(ArgAction::SetTrue)
+            .overrides_with("summary")
+            .help("Don't print the summary"),
+        Arg::new("chdir")
+            .long("chdir")
+            .action(ArgAction::SetTrue)
+            .help("Run the command from the mounted counterpart of the current directory"),
+        Arg::new("in-place")
+            .long("in-place")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("chdir")
+            .help("Run the command in a private namespace where SOURCE itself is the mount"),
+        Arg::new("command")
+            .num_args(1..)
+            .index(3)
+            .last(true)
+            .help("The command to execute, in place of the task's"),
+    ]
+}
+
+fn source_or_task(arg: Arg) -> Arg {
+    arg.help("A task from the nearest swatch.toml, or else the directory to monitor")
+}
+
+fn cli() -> Command {
+    Command::new("swatch")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("run")
+                .about("Run a command against SOURCE once and report what it accessed")
+                .args(mount_args())
+                .args(filter_args())
+                .mut_arg("SOURCE", source_or_task)
+                .args(depfile_args())
+                .args(command_args()),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Run a command, and run it again whenever something it accessed changes")
+                .args(mount_args())
+                .args(filter_args())
+                .mut_arg("SOURCE", source_or_task)
+                .args(depfile_args())
+                .args(command_args())
+                .arg(
+                    Arg::new("debounce")
+                        .long("debounce")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Wait for MS milliseconds without changes before re-running [default: 100]"),
+                ),
+        )
+        .subcommand(
+            Command::new("mount")
+                .about("Mount SOURCE and record accesses until it is unmounted")
+                .args(mount_args())
+                .args(filter_args())
+                .mut_arg("MOUNT_POINT", |arg| {
+                    arg.required(true).help("Mount FUSE at given path")
+                }),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Summarize a trace saved with --log")
+                .arg(
+                    Arg::new("TRACE")
+                        .required(true)
+                        .index(1)
+                        .help("The log file to read, written in the text or jsonl format"),
+                )
+                .arg(
+                    Arg::new("source")
+                        .long("source")
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Where the traced SOURCE is, for filtering and for paths in the depfile"),
+                )
+                .args(filter_args())
+                .args(depfile_args()),
+        )
+}
+
+fn mount_options(matches: &ArgMatches) -> Vec<MountOption> {
+    let mut options = vec![
+        if matches.get_flag("read-write") {
+            MountOption::RW
+        } else {
+            MountOption::RO
         },
+        MountOption::FSName("swatch".to_string()),
+        // Every request is served with swatch's own credentials, so the
+        // kernel has to check permissions against the caller before that
+        MountOption::DefaultPermissions,
+        MountOption::AutoUnmount,
+    ];
+    if matches.get_flag("allow-other") {
+        options.push(MountOption::AllowOther);
+    } else if matches.get_flag("allow-root") {
+        options.push(MountOption::AllowRoot);
+    }
+    options
+}
+
+/// Builds the filter for `source` from the command line, adding to the
+/// patterns from swatch.toml and the task if there are any.
+fn filter(
+    matches: &ArgMatches,
+    source: &Path,
+    config: Option<&Config>,
+    task: Option<&Task>,
+) -> io::Result<Filter> {
+    let mut include = Vec::new();
+    let mut ignore = Vec::new();
+    let mut gitignore = matches.get_flag("gitignore");
+    if let Some(config) = config {
+        include.extend(config.include.iter().cloned());
+        ignore.extend(config.ignore.iter().cloned());
+        gitignore |= config.gitignore;
+    }
+    if let Some(task) = task {
+        include.extend(task.include.iter().cloned());
+        ignore.extend(task.ignore.iter().cloned());
+        gitignore |= task.gitignore;
     }
+    include.extend(
+        matches
+            .get_many::<String>("include")
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    ignore.extend(
+        matches
+            .get_many::<String>("ignore")
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    Filter::new(source, &include, &ignore, gitignore)
+}
+
+/// Sets up the filesystem for `source`, along with the recorder it feeds.
+fn swatch_fs(
+    matches: &ArgMatches,
+    source: &Path,
+    filter: Arc<Filter>,
+    ttl: Duration,
+    tracking: bool,
+) -> io::Result<(SwatchFS, Arc<Mutex<Recorder>>)> {
+    let root = Dir::open(source)?;
+    let log = match matches.get_one::<String>("log") {
+        Some(path) => Some(File::create(path)?),
+        None => None,
+    };
+    let format = Format::from_name(matches.get_one::<String>("format").unwrap()).unwrap();
+    let recorder = if tracking {
+        Recorder::new(log, format, filter)
+    } else {
+        Recorder::log_only(log, format, filter)
+    };
+    let recorder = Arc::new(Mutex::new(recorder));
+    let writable = matches.get_flag("read-write");
+    let fs = SwatchFS::new(root, source.to_owned(), writable, ttl, recorder.clone());
+    Ok((fs, recorder))
+}
+
+fn write_depfile(
+    matches: &ArgMatches,
+    path: &Path,
+    target: &str,
+    source: &Path,
+    recorder: &Recorder,
+) -> io::Result<()> {
+    let deps: Vec<_> = recorder.inputs().map(|p| source.join(p)).collect();
+    // By default missing paths are left to the watch set, so the target can
+    // become up to date; see `depfile::write`
+    let missing: Vec<_> = if matches.get_flag("depfile-missing") {
+        recorder.missing().map(|p| source.join(p)).collect()
+    } else {
+        Vec::new()
+    };
+    let mut out = io::BufWriter::new(File::create(path)?);
+    depfile::write(
+        &mut out,
+        target,
+        deps.iter().map(|p| p.as_path()),
+        missing.iter().map(|p| p.as_path()),
+    )?;
+    out.flush()
+}
+
+fn print_summary<W: Write>(out: &mut W, recorder: &Recorder) -> io::Result<()> {
+    for (path, class) in recorder.classes() {
+        writeln!(out, "{:<12} {}", class.name(), path.display())?;
+    }
+    for path in recorder.missing() {
+        writeln!(out, "{:<12} {}", "missing", path.display())?;
+    }
+    Ok(())
+}
+
+/// Returns where the current directory appears under the mount, or the mount
+/// itself when we are running from outside SOURCE.
+fn child_cwd(source: &Path, mount: &Path) -> io::Result<PathBuf> {
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    Ok(match cwd.strip_prefix(source) {
+        Ok(rel) => mount.join(rel),
+        Err(_) => mount.to_owned(),
+    })
+}
+
+/// Maps the child's status onto ours the way a shell would: its exit code, or
+/// 128 plus the signal that killed it.
+fn exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => (128 + signal) as u8,
+        (None, None) => 1,
+    }
+}
+
+/// What the SOURCE argument turned out to name.
+struct Source {
+    dir: PathBuf,
+    /// The swatch.toml that applies to `dir`, if there is one.
+    config: Option<Config>,
+    task: Option<Task>,
 }
 
-impl Filesystem for SwatchFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let meta = self.root.metadata(name);
-        if let Err(x) = meta {
-            if x.kind() == ErrorKind::NotFound {
-                reply.error(ENOENT)
+/// Works out what SOURCE stands for: a task in the nearest swatch.toml, whose
+/// directory is then SOURCE, or failing that a directory.
+fn source_and_task(source: &str) -> Result<Source, Box<dyn Error>> {
+    // Anything that reads as a path, like ./test, is never taken for a task
+    let path = Path::new(source);
+    if path.components().count() == 1 && path.file_name().is_some() {
+        if let Some((dir, mut config)) = config::discover(&std::env::current_dir()?)? {
+            if let Some(task) = config.tasks.remove(source) {
+                return Ok(Source {
+                    dir,
+                    config: Some(config),
+                    task: Some(task),
+                });
             }
-        } else if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &meta_into_file_attr(&meta.unwrap()), 0);
-        } else {
-            reply.error(ENOENT);
         }
     }
+    if !path.is_dir() {
+        return Err(format!(
+            "{} is neither a task in {} nor a directory",
+            source,
+            config::FILE_NAME
+        )
+        .into());
+    }
+    let config = config::discover(&std::fs::canonicalize(path)?)?.map(|(_, config)| config);
+    Ok(Source {
+        dir: path.to_owned(),
+        config,
+        task: None,
+    })
+}
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        println!("{:?} {:?}", _req, ino);
-        match ino {
-            1 => reply.attr(
-                &TTL,
-                &meta_into_file_attr(&self.root.self_metadata().unwrap()),
-            ),
-            //2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
+/// Runs the command on a mount of SOURCE, and when watching, again every time
+/// something it accessed changes. Anything not given on the command line is
+/// taken from the task SOURCE names, if it names one.
+fn run(matches: &ArgMatches, watching: bool) -> Result<ExitCode, Box<dyn Error>> {
+    let Source {
+        dir: sourcepoint,
+        config,
+        task,
+    } = source_and_task(matches.get_one::<String>("SOURCE").unwrap())?;
+    let command: Vec<String> = match (matches.get_many::<String>("command"), &task) {
+        (Some(command), _) => command.cloned().collect(),
+        (None, Some(task)) => task.command.clone(),
+        (None, None) => return Err("no command given".into()),
+    };
+    let depfile = match (matches.get_one::<String>("depfile"), &task) {
+        (Some(path), _) => {
+            let target = matches.get_one::<String>("depfile-target").unwrap();
+            Some((PathBuf::from(path), target.clone()))
+        }
+        (None, Some(task)) => task
+            .depfile
+            .as_ref()
+            .zip(task.depfile_target.clone())
+            .map(|(path, target)| (sourcepoint.join(path), target)),
+        (None, None) => None,
+    };
+    let debounce = watching.then(|| {
+        let ms = matches.get_one::<u64>("debounce").copied();
+        Duration::from_millis(ms.or(task.as_ref().and_then(|t| t.debounce)).unwrap_or(100))
+    });
+
+    // Declared before the mount so that it is only removed after unmounting
+    let temp_mount;
+    let mountpoint = match matches.get_one::<String>("MOUNT_POINT") {
+        Some(mountpoint) => Path::new(mountpoint),
+        None => {
+            temp_mount = TempDir::new("swatch")?;
+            temp_mount.path()
+        }
+    };
+    // Absolute, so the child can translate paths from wherever it runs
+    let source_abs = std::fs::canonicalize(&sourcepoint)?;
+    let mount_abs = std::fs::canonicalize(mountpoint)?;
+    let filter = Arc::new(filter(
+        matches,
+        &source_abs,
+        config.as_ref(),
+        task.as_ref(),
+    )?);
+    // A rerun follows a change within the debounce, sooner than cached
+    // attributes would expire, and must not see the old size
+    let ttl = if watching { Duration::ZERO } else { fs::TTL };
+    let (fs, recorder) = swatch_fs(matches, &source_abs, filter.clone(), ttl, true)?;
+    let mounted = fuser::spawn_mount2(fs, mountpoint, &mount_options(matches))?;
+    // From here on, a signal stops the loop so that everything below runs
+    signals::install()?;
+
+    let status = loop {
+        let status = {
+            use std::process::Command;
+            let mut cmd = Command::new(&command[0]);
+            cmd.args(&command[1..]);
+            if let Some(task) = &task {
+                cmd.envs(&task.env);
+            }
+            cmd.env("SWATCH_SOURCE", &source_abs);
+            cmd.env("SWATCH_MOUNT", &mount_abs);
+            if matches.get_flag("chdir") {
+                cmd.current_dir(child_cwd(&source_abs, &mount_abs)?);
+            }
+            if matches.get_flag("in-place") {
+                namespace::overlay_in_place(&mut cmd, &mount_abs, &source_abs)?;
+            }
+            let mut child = cmd.spawn()?;
+            signals::forward_to(Some(child.id()));
+            let status = child.wait();
+            signals::forward_to(None);
+            status?
+        };
+        if signals::caught().is_some() {
+            break status;
         }
-    }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        if ino == 2 {
-            reply.data(&"".as_bytes()[offset as usize..]);
+        // Once is a report; every rerun in watch mode would be noise
+        let summary = if watching {
+            matches.get_flag("summary")
         } else {
-            reply.error(ENOENT);
+            !matches.get_flag("no-summary")
+        };
+        if summary {
+            print_summary(&mut io::stderr().lock(), &recorder.lock().unwrap())?;
         }
-    }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+        if let Some((path, target)) = &depfile {
+            write_depfile(
+                matches,
+                path,
+                target,
+                &sourcepoint,
+                &recorder.lock().unwrap(),
+            )?;
         }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
-        ];
+        let Some(debounce) = debounce else {
+            break status;
+        };
+        let seen: Vec<Seen> = {
+            let mut recorder = recorder.lock().unwrap();
+            let accessed = recorder.accessed().map(|(path, at)| Seen {
+                path: path.to_owned(),
+                at: Some(at),
+            });
+            let missing = recorder.missing().map(|path| Seen {
+                path: path.to_owned(),
+                at: None,
+            });
+            let seen = accessed.chain(missing).collect();
+            recorder.reset();
+            seen
+        };
+        match watch::wait_for_change(&sourcepoint, &seen, &filter, debounce) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => break status,
+            res => res?,
+        }
+    };
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
+    // Unmounts, then waits for the session thread to finish
+    mounted.join();
+
+    Ok(ExitCode::from(exit_code(status)))
+}
+
+/// Serves the mount in the foreground until something unmounts it.
+fn mount(matches: &ArgMatches) -> Result<ExitCode, Box<dyn Error>> {
+    let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
+    let source = std::fs::canonicalize(matches.get_one::<String>("SOURCE").unwrap())?;
+    let config = config::discover(&source)?.map(|(_, config)| config);
+    let filter = Arc::new(filter(matches, &source, config.as_ref(), None)?);
+    let (fs, _recorder) = swatch_fs(matches, &source, filter, fs::TTL, false)?;
+    fuser::mount2(fs, mountpoint, &mount_options(matches))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Replays a saved log and reports on it as if the command had just run.
+fn report(matches: &ArgMatches) -> Result<ExitCode, Box<dyn Error>> {
+    let name = matches.get_one::<String>("TRACE").unwrap();
+    let mut lines = io::BufReader::new(File::open(name)?).lines().peekable();
+    let source = Path::new(matches.get_one::<String>("source").unwrap());
+    let filter = filter(matches, source, None, None)?;
+    let mut recorder = Recorder::new(None, Format::Text, Arc::new(filter));
+
+    // Only JSON Lines traces have a header; text traces start with an event
+    let mut parse: fn(&str) -> Option<Event> = Event::parse;
+    if let Some(Ok(first)) = lines.peek() {
+        if first.trim() == "[" {
+            return Err(format!("{}: Chrome traces can't be read back", name).into());
+        }
+        if jsonl::read_header(first).map_err(|e| format!("{}: {}", name, e))? {
+            parse = jsonl::parse_event;
+            lines.next();
+        }
+    }
+    let mut events = 0;
+    for line in lines {
+        let line = line?;
+        match parse(&line) {
+            Some(event) => {
+                recorder.add(event);
+                events += 1;
             }
+            None => log::warn!("skipping unrecognized trace line: {}", line),
         }
-        reply.ok();
     }
-}
+    if events == 0 {
+        return Err(format!("{}: no events in the text or jsonl format", name).into());
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Command::new("hello")
-        .version(crate_version!())
-        .author("Christopher Berner")
-        .arg(
-            Arg::new("SOURCE")
-                .required(true)
-                .index(1)
-                .help("Directory to monitor"),
-        )
-        .arg(
-            Arg::new("MOUNT_POINT")
-                .required(true)
-                .index(2)
-                .help("Act as a client, and mount FUSE at given path"),
-        )
-        .arg(
-            Arg::new("allow-root")
-                .long("allow-root")
-                .action(ArgAction::SetTrue)
-                .help("Allow root user to access filesystem"),
-        )
-        .arg(
-            Arg::new("command")
-                .required(true)
-                .num_args(1..)
-                .index(3)
-                .last(true)
-                .help("The command to execute"),
-        );
-    let matches = args.get_matches();
+    print_summary(&mut io::stdout().lock(), &recorder)?;
 
-    env_logger::init();
-    let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
-    let sourcepoint = matches.get_one::<String>("SOURCE").unwrap();
-    let options = vec![
-        MountOption::RO,
-        MountOption::FSName("hello".to_string()),
-        MountOption::AllowOther,
-        MountOption::AutoUnmount,
-    ];
-    let root = Dir::open(sourcepoint)?;
-    let mounted = fuser::spawn_mount2(SwatchFS { root }, mountpoint, &options).unwrap();
-
-    {
-        use std::process::Command;
-        let mut p = matches.get_many::<String>("command").unwrap();
-        let mut cmd = Command::new(p.next().unwrap());
-        cmd.args(p);
-        cmd.spawn().unwrap();
+    if let Some(path) = matches.get_one::<String>("depfile") {
+        let target = matches.get_one::<String>("depfile-target").unwrap();
+        write_depfile(matches, Path::new(path), target, source, &recorder)?;
     }
 
-    mounted.join();
+    Ok(ExitCode::SUCCESS)
+}
 
-    Ok(())
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let matches = cli().get_matches();
+
+    env_logger::init();
+    match matches.subcommand() {
+        Some(("run", matches)) => run(matches, false),
+        Some(("watch", matches)) => run(matches, true),
+        Some(("mount", matches)) => mount(matches),
+        Some(("report", matches)) => report(matches),
+        _ => unreachable!("clap requires a subcommand"),
+    }
 }
@@ -0,0 +1,67 @@
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Arranges for `cmd` to run in its own user and mount namespace, where the
+/// swatch mount at `mount` is bind-mounted over `source`. The command then
+/// sees SOURCE at its usual path while every access still goes through swatch.
+#[cfg(target_os = "linux")]
+pub fn overlay_in_place(cmd: &mut Command, mount: &Path, source: &Path) -> io::Result<()> {
+    use crate::os::check;
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+
+    // Everything the child needs is prepared here, since only
+    // async-signal-safe calls are allowed between fork and exec
+    let mount = CString::new(mount.as_os_str().as_bytes())?;
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let cwd = CString::new(std::env::current_dir()?.into_os_string().as_bytes())?;
+    let uid_map = format!("{0} {0} 1", unsafe { libc::getuid() });
+    let gid_map = format!("{0} {0} 1", unsafe { libc::getgid() });
+
+    fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+        unsafe {
+            let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            check(fd)?;
+            let res = libc::write(fd, contents.as_ptr().cast(), contents.len());
+            libc::close(fd);
+            check(res as libc::c_int)
+        }
+    }
+
+    let hook = move || unsafe {
+        check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+        // Keep our own identity inside the namespace
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+        write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+        // Don't let the overlay propagate back out to the caller's namespace
+        check(libc::mount(
+            std::ptr::null(),
+            c"/".as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ))?;
+        check(libc::mount(
+            mount.as_ptr(),
+            source.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND | libc::MS_REC,
+            std::ptr::null(),
+        ))?;
+        // Re-resolve the working directory in case it lies under the overlay
+        check(libc::chdir(cwd.as_ptr()))
+    };
+    unsafe { cmd.pre_exec(hook) };
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn overlay_in_place(_cmd: &mut Command, _mount: &Path, _source: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "mounting in place requires Linux namespaces",
+    ))
+}
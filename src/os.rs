@@ -0,0 +1,17 @@
+//! Turning what libc calls return into `io::Result`s.
+
+use std::io;
+
+/// Fails with `errno` if `res` is negative, as libc calls signal errors.
+pub fn check(res: libc::c_int) -> io::Result<()> {
+    check_len(res as isize).map(drop)
+}
+
+/// Like `check`, for calls that return a length on success.
+pub fn check_len(res: isize) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
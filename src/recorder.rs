@@ -0,0 +1,604 @@
+use crate::chrome;
+use crate::filter::Filter;
+use crate::jsonl;
+use chrono::{DateTime, SecondsFormat, Utc};
+use fuser::Request;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Lookup,
+    /// A lookup of a name that does not exist.
+    Missing,
+    Getattr,
+    Open,
+    /// An open for writing, which may truncate.
+    OpenWrite,
+    Read,
+    Readdir,
+    Readlink,
+    Write,
+    Create,
+    Mkdir,
+    Mknod,
+    Unlink,
+    Rmdir,
+    Rename,
+    Symlink,
+    Setattr,
+    Getxattr,
+    Listxattr,
+    Setxattr,
+    Removexattr,
+}
+
+impl Op {
+    const ALL: [Op; 21] = [
+        Op::Lookup,
+        Op::Missing,
+        Op::Getattr,
+        Op::Open,
+        Op::OpenWrite,
+        Op::Read,
+        Op::Readdir,
+        Op::Readlink,
+        Op::Write,
+        Op::Create,
+        Op::Mkdir,
+        Op::Mknod,
+        Op::Unlink,
+        Op::Rmdir,
+        Op::Rename,
+        Op::Symlink,
+        Op::Setattr,
+        Op::Getxattr,
+        Op::Listxattr,
+        Op::Setxattr,
+        Op::Removexattr,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Op> {
+        Op::ALL.into_iter().find(|op| op.name() == name)
+    }
+
+    /// Whether the operation changes what is at the path.
+    pub fn writes(self) -> bool {
+        matches!(
+            self,
+            Op::OpenWrite
+                | Op::Write
+                | Op::Create
+                | Op::Mkdir
+                | Op::Mknod
+                | Op::Symlink
+                | Op::Setattr
+                | Op::Setxattr
+                | Op::Removexattr
+        )
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Lookup => "lookup",
+            Op::Missing => "missing",
+            Op::Getattr => "getattr",
+            Op::Open => "open",
+            Op::OpenWrite => "open-write",
+            Op::Read => "read",
+            Op::Readdir => "readdir",
+            Op::Readlink => "readlink",
+            Op::Write => "write",
+            Op::Create => "create",
+            Op::Mkdir => "mkdir",
+            Op::Mknod => "mknod",
+            Op::Unlink => "unlink",
+            Op::Rmdir => "rmdir",
+            Op::Rename => "rename",
+            Op::Symlink => "symlink",
+            Op::Setattr => "setattr",
+            Op::Getxattr => "getxattr",
+            Op::Listxattr => "listxattr",
+            Op::Setxattr => "setxattr",
+            Op::Removexattr => "removexattr",
+        }
+    }
+}
+
+/// What a path turned out to be for the command, judged from the order in
+/// which it was read and written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Read before anything wrote to it.
+    Input,
+    /// Created or written, and not read back.
+    Output,
+    /// Written, then read back.
+    Intermediate,
+    /// Removed, or renamed away.
+    Deleted,
+}
+
+impl Class {
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Input => "input",
+            Class::Output => "output",
+            Class::Intermediate => "intermediate",
+            Class::Deleted => "deleted",
+        }
+    }
+
+    fn after(current: Option<Class>, op: Op) -> Option<Class> {
+        match op {
+            Op::Open | Op::Read | Op::Readlink => match current {
+                None => Some(Class::Input),
+                Some(Class::Output) => Some(Class::Intermediate),
+                other => other,
+            },
+            _ if op.writes() => match current {
+                Some(Class::Input) | Some(Class::Intermediate) => current,
+                _ => Some(Class::Output),
+            },
+            Op::Unlink | Op::Rmdir | Op::Rename => Some(Class::Deleted),
+            _ => current,
+        }
+    }
+}
+
+/// How the access log is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One line per event, as `Event`'s `Display` writes it.
+    Text,
+    /// JSON Lines, as described in the `jsonl` module.
+    Jsonl,
+    /// Chrome's Trace Event Format, as described in the `chrome` module.
+    Chrome,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "jsonl" => Some(Format::Jsonl),
+            "chrome" => Some(Format::Chrome),
+            _ => None,
+        }
+    }
+}
+
+/// One filesystem operation performed through the mount.
+pub struct Event {
+    pub op: Op,
+    /// Path relative to SOURCE; empty for SOURCE itself.
+    pub path: PathBuf,
+    /// For a rename, where `path` was moved to; for a symlink, what it
+    /// points at.
+    pub target: Option<PathBuf>,
+    pub ino: u64,
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// For reads, writes and directory listings, where they started.
+    pub offset: Option<i64>,
+    /// For reads and writes, how many bytes were asked for.
+    pub size: Option<u64>,
+    /// What the operation failed with, or 0 if it succeeded.
+    pub errno: i32,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl Event {
+    /// Starts timing an operation `req` made; the rest is filled in as it goes.
+    pub fn start(req: &Request, op: Op, ino: u64, path: &Path) -> Event {
+        let now = SystemTime::now();
+        Event {
+            op,
+            path: path.to_owned(),
+            target: None,
+            ino,
+            pid: req.pid(),
+            uid: req.uid(),
+            gid: req.gid(),
+            offset: None,
+            size: None,
+            errno: 0,
+            start: now,
+            end: now,
+        }
+    }
+
+    /// Parses a line of the access log back into the event it was written for.
+    pub fn parse(line: &str) -> Option<Event> {
+        let mut fields = line.splitn(8, ' ');
+        let time = DateTime::parse_from_rfc3339(fields.next()?).ok()?;
+        let op = Op::from_name(fields.next()?)?;
+        let mut number = |key: &str| fields.next()?.strip_prefix(key)?.parse::<u64>().ok();
+        let ino = number("ino=")?;
+        let pid = number("pid=")?.try_into().ok()?;
+        let uid = number("uid=")?.try_into().ok()?;
+        let gid = number("gid=")?.try_into().ok()?;
+        let errno = number("errno=")?.try_into().ok()?;
+        let rest = fields.next()?;
+        // A readlink that failed has no target to show
+        let (path, target) = match (op, rest.split_once(" -> ")) {
+            (Op::Rename | Op::Readlink | Op::Symlink, Some((path, target))) => {
+                (path, Some(parse_target(op, target)?))
+            }
+            _ => (rest, None),
+        };
+        Some(Event {
+            op,
+            path: parse_path(path)?,
+            target,
+            ino,
+            pid,
+            uid,
+            gid,
+            offset: None,
+            size: None,
+            errno,
+            start: time.into(),
+            end: time.into(),
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time: DateTime<Utc> = self.start.into();
+        // The path goes last since it may contain spaces
+        write!(
+            f,
+            "{} {} ino={} pid={} uid={} gid={} errno={} {}",
+            time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.op.name(),
+            self.ino,
+            self.pid,
+            self.uid,
+            self.gid,
+            self.errno,
+            display_path(&self.path).display(),
+        )?;
+        if let Some(target) = &self.target {
+            write!(f, " -> {}", display_path(target).display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back a path written with `display_path`, refusing any that would
+/// leave SOURCE.
+pub fn parse_path(path: &str) -> Option<PathBuf> {
+    if path == "." {
+        return Some(PathBuf::new());
+    }
+    let path = PathBuf::from(path);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(path)
+}
+
+/// Reads back the target of an `op` event. Only a rename's is a path under
+/// SOURCE; a symlink's is whatever the link holds.
+pub fn parse_target(op: Op, target: &str) -> Option<PathBuf> {
+    match op {
+        Op::Rename => parse_path(target),
+        _ => Some(PathBuf::from(target)),
+    }
+}
+
+pub fn display_path(path: &Path) -> &Path {
+    if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    }
+}
+
+/// The access log, along with what a format needs to remember between events.
+struct Log {
+    out: LineWriter<File>,
+    format: Format,
+    trace: chrome::Trace,
+}
+
+/// Collects the accesses `SwatchFS` serves and writes them to the access log.
+pub struct Recorder {
+    log: Option<Log>,
+    filter: Arc<Filter>,
+    /// Whether to keep what was accessed, rather than only logging it.
+    tracking: bool,
+    classes: BTreeMap<PathBuf, Class>,
+    /// When the command last saw each path as it is: when it first looked,
+    /// or when it last changed it.
+    accessed: BTreeMap<PathBuf, SystemTime>,
+    missing: BTreeSet<PathBuf>,
+}
+
+impl Recorder {
+    pub fn new(log: Option<File>, format: Format, filter: Arc<Filter>) -> Recorder {
+        let mut recorder = Recorder {
+            log: log.map(|log| Log {
+                out: LineWriter::new(log),
+                format,
+                trace: chrome::Trace::new(),
+            }),
+            filter,
+            tracking: true,
+            classes: BTreeMap::new(),
+            accessed: BTreeMap::new(),
+            missing: BTreeSet::new(),
+        };
+        recorder.write_log(|log| match log.format {
+            Format::Text => Ok(()),
+            Format::Jsonl => jsonl::write_header(&mut log.out),
+            Format::Chrome => log.trace.write_header(&mut log.out),
+        });
+        recorder
+    }
+
+    /// A recorder that only writes the log, for mounts that stay up for
+    /// longer than any one command and would otherwise keep every path.
+    pub fn log_only(log: Option<File>, format: Format, filter: Arc<Filter>) -> Recorder {
+        let mut recorder = Recorder::new(log, format, filter);
+        recorder.tracking = false;
+        recorder
+    }
+
+    /// Files whose contents were read through the mount before anything
+    /// wrote to them, relative to SOURCE.
+    pub fn inputs(&self) -> impl Iterator<Item = &Path> {
+        self.classes()
+            .filter(|&(_, class)| class == Class::Input)
+            .map(|(path, _)| path)
+    }
+
+    /// Every path that was read, written or removed, with what it was to the
+    /// command.
+    pub fn classes(&self) -> impl Iterator<Item = (&Path, Class)> {
+        self.classes
+            .iter()
+            .map(|(path, &class)| (path.as_path(), class))
+    }
+
+    /// Every file and directory touched through the mount that the command
+    /// did not produce itself, relative to SOURCE, with when the command last
+    /// saw it as it is. Anything that changed after that without the command
+    /// doing it may have left its result stale.
+    pub fn accessed(&self) -> impl Iterator<Item = (&Path, SystemTime)> {
+        self.accessed
+            .iter()
+            .filter(|(path, _)| matches!(self.classes.get(*path), None | Some(Class::Input)))
+            .map(|(path, &at)| (path.as_path(), at))
+    }
+
+    /// Paths the command looked for but did not find, and did not go on to
+    /// create itself. If one of them appears, the command might behave
+    /// differently.
+    pub fn missing(&self) -> impl Iterator<Item = &Path> {
+        self.missing
+            .iter()
+            .filter(|path| !self.classes.contains_key(*path))
+            .map(PathBuf::as_path)
+    }
+
+    /// Forgets what has been accessed so far, ready for another run.
+    pub fn reset(&mut self) {
+        self.classes.clear();
+        self.accessed.clear();
+        self.missing.clear();
+    }
+
+    /// Takes in an event, whether fresh from the mount or read back from a
+    /// saved log, unless the filter leaves out everything it touches.
+    pub fn add(&mut self, event: Event) {
+        let op = event.op;
+        let included = self.filter.includes(&event.path);
+        // A file is often written under an ignored name and renamed into place
+        let target_included = match (op, &event.target) {
+            (Op::Rename, Some(target)) => self.filter.includes(target),
+            _ => false,
+        };
+        if !included && !target_included {
+            return;
+        }
+        // A failed operation changed nothing, so it only goes in the log; a
+        // missing path is the exception, since its failure is the point
+        if self.tracking && (event.errno == 0 || op == Op::Missing) {
+            if included {
+                self.classify(&event.path, op);
+                if op == Op::Missing {
+                    self.missing.insert(event.path.clone());
+                } else if op.writes() {
+                    self.accessed.insert(event.path.clone(), event.end);
+                } else {
+                    self.accessed
+                        .entry(event.path.clone())
+                        .or_insert(event.start);
+                }
+            }
+            if let (true, Some(target)) = (target_included, &event.target) {
+                // Whatever was renamed into place is new there
+                self.classify(target, Op::Create);
+            }
+        }
+        self.write_log(|log| match log.format {
+            Format::Text => writeln!(log.out, "{}", event),
+            Format::Jsonl => jsonl::write_event(&mut log.out, &event),
+            Format::Chrome => log.trace.write_event(&mut log.out, &event),
+        });
+    }
+
+    fn write_log(&mut self, write: impl FnOnce(&mut Log) -> io::Result<()>) {
+        if let Some(log) = &mut self.log {
+            if let Err(e) = write(log) {
+                log::warn!("disabling access log after write failure: {}", e);
+                self.log = None;
+            }
+        }
+    }
+
+    fn classify(&mut self, path: &Path, op: Op) {
+        let current = self.classes.get(path).copied();
+        if let Some(class) = Class::after(current, op) {
+            self.classes.insert(path.to_owned(), class);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.write_log(|log| match log.format {
+            Format::Text | Format::Jsonl => Ok(()),
+            Format::Chrome => log.trace.write_footer(&mut log.out),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn event(op: Op, path: &str, target: Option<&str>) -> Event {
+        // Whole microseconds, as the text log keeps no more
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_734_000_000_123_456);
+        Event {
+            op,
+            path: PathBuf::from(path),
+            target: target.map(PathBuf::from),
+            ino: 42,
+            pid: 1000,
+            uid: 1001,
+            gid: 1002,
+            offset: None,
+            size: None,
+            errno: 2,
+            start: time,
+            end: time,
+        }
+    }
+
+    fn round_trip(event: &Event) -> Event {
+        let line = event.to_string();
+        Event::parse(&line).unwrap_or_else(|| panic!("unparseable: {}", line))
+    }
+
+    fn assert_same(a: &Event, b: &Event) {
+        assert_eq!(a.op, b.op);
+        assert_eq!(a.path, b.path);
+        assert_eq!(a.target, b.target);
+        assert_eq!(
+            (a.ino, a.pid, a.uid, a.gid, a.errno),
+            (b.ino, b.pid, b.uid, b.gid, b.errno)
+        );
+        assert_eq!(a.start, b.start);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        for event in [
+            event(Op::Read, "src/main.rs", None),
+            event(Op::Lookup, "", None),
+            event(Op::Open, "a file with spaces", None),
+            event(Op::Rename, "old name", Some("dir/new name")),
+            event(Op::Rename, "dir", Some("")),
+            event(Op::Readlink, "link", Some("../target")),
+            event(Op::Symlink, "link", Some("target")),
+        ] {
+            assert_same(&event, &round_trip(&event));
+        }
+    }
+
+    #[test]
+    fn root_is_dot() {
+        let line = event(Op::Getattr, "", None).to_string();
+        assert!(line.ends_with(" errno=2 ."), "{}", line);
+        let line = event(Op::Rename, "a", Some("")).to_string();
+        assert!(line.ends_with(" a -> ."), "{}", line);
+    }
+
+    #[test]
+    fn arrow_only_splits_targets() {
+        // Only operations with a target split the rest of the line at an arrow
+        let open = event(Op::Open, "a -> b", None);
+        assert_same(&open, &round_trip(&open));
+    }
+
+    #[test]
+    fn paths_outside_source_are_refused() {
+        let line = event(Op::Read, "x", None).to_string();
+        for path in ["/etc/passwd", "../x", "a/../../x"] {
+            let line = line.replace(" x", &format!(" {}", path));
+            assert!(Event::parse(&line).is_none(), "{}", line);
+        }
+        let line = event(Op::Rename, "a", Some("b")).to_string();
+        assert!(Event::parse(&line.replace("-> b", "-> /b")).is_none());
+        // A symlink may point anywhere
+        let link = event(Op::Symlink, "link", Some("/etc/passwd"));
+        assert_same(&link, &round_trip(&link));
+    }
+
+    #[test]
+    fn log_only_keeps_nothing() {
+        let filter = Arc::new(Filter::new(Path::new("/src"), &[], &[], false).unwrap());
+        let succeeded = |op, path| Event {
+            errno: 0,
+            ..event(op, path, None)
+        };
+        let mut recorder = Recorder::log_only(None, Format::Text, filter.clone());
+        recorder.add(succeeded(Op::Read, "a"));
+        recorder.add(succeeded(Op::Missing, "b"));
+        assert_eq!(recorder.classes().count(), 0);
+        assert_eq!(recorder.accessed().count(), 0);
+        assert_eq!(recorder.missing().count(), 0);
+
+        let mut recorder = Recorder::new(None, Format::Text, filter);
+        recorder.add(succeeded(Op::Read, "a"));
+        assert_eq!(recorder.inputs().collect::<Vec<_>>(), [Path::new("a")]);
+    }
+
+    fn class_after(ops: &[Op]) -> Option<Class> {
+        ops.iter().fold(None, |class, &op| Class::after(class, op))
+    }
+
+    #[test]
+    fn read_then_write_is_input() {
+        assert_eq!(class_after(&[Op::Read]), Some(Class::Input));
+        assert_eq!(
+            class_after(&[Op::Open, Op::Read, Op::OpenWrite, Op::Write]),
+            Some(Class::Input)
+        );
+    }
+
+    #[test]
+    fn write_then_read_is_intermediate() {
+        assert_eq!(class_after(&[Op::Create, Op::Write]), Some(Class::Output));
+        assert_eq!(
+            class_after(&[Op::Create, Op::Write, Op::Open, Op::Read]),
+            Some(Class::Intermediate)
+        );
+        assert_eq!(
+            class_after(&[Op::Create, Op::Open, Op::Read, Op::Write]),
+            Some(Class::Intermediate)
+        );
+    }
+
+    #[test]
+    fn lookups_leave_the_class_alone() {
+        assert_eq!(class_after(&[Op::Lookup, Op::Getattr]), None);
+        assert_eq!(
+            class_after(&[Op::Create, Op::Lookup, Op::Getattr]),
+            Some(Class::Output)
+        );
+        assert_eq!(class_after(&[Op::Read, Op::Unlink]), Some(Class::Deleted));
+    }
+}
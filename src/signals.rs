@@ -0,0 +1,80 @@
+//! Catches SIGINT and SIGTERM so that swatch can pass on those the command
+//! didn't get itself, then unmount and clean up rather than dying with the
+//! mount in place.
+
+use crate::os::check;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static CAUGHT: AtomicI32 = AtomicI32::new(0);
+static CHILD: AtomicI32 = AtomicI32::new(0);
+// Written to when a signal is caught, so that a poll can wake up for it
+static PIPE_READ: AtomicI32 = AtomicI32::new(-1);
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+pub fn install() -> io::Result<()> {
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    for fd in fds {
+        check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        check(unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) })?;
+    }
+    PIPE_READ.store(fds[0], Ordering::SeqCst);
+    PIPE_WRITE.store(fds[1], Ordering::SeqCst);
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handle
+            as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void)
+            as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        check(unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) })?;
+    }
+    Ok(())
+}
+
+/// The signal that was caught, if any.
+pub fn caught() -> Option<i32> {
+    match CAUGHT.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// A descriptor that becomes readable once a signal has been caught.
+pub fn fd() -> RawFd {
+    PIPE_READ.load(Ordering::SeqCst)
+}
+
+/// Sets the child that caught signals are passed on to, if any.
+pub fn forward_to(child: Option<u32>) {
+    let pid = child.map_or(0, |pid| pid as i32);
+    CHILD.store(pid, Ordering::SeqCst);
+    // A signal caught before the child was known would otherwise be lost
+    if let (Some(signal), true) = (caught(), pid > 0) {
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+// Only async-signal-safe calls in here
+extern "C" fn handle(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    CAUGHT.store(signal, Ordering::SeqCst);
+    // What the kernel sends, like a Ctrl-C at the terminal, went to the whole
+    // foreground process group, child included; a second would make many
+    // programs abandon their cleanup. Only pass on what a process sent.
+    let from_process = unsafe { (*info).si_code } <= 0;
+    let pid = CHILD.load(Ordering::SeqCst);
+    if pid > 0 && from_process {
+        unsafe { libc::kill(pid, signal) };
+    }
+    let byte = 1u8;
+    unsafe {
+        libc::write(
+            PIPE_WRITE.load(Ordering::SeqCst),
+            (&byte as *const u8).cast(),
+            1,
+        )
+    };
+}
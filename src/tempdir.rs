@@ -0,0 +1,38 @@
+use std::ffi::{CString, OsString};
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// A private directory under the system temp dir, removed again on drop.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> io::Result<TempDir> {
+        let template = std::env::temp_dir().join(format!("{}.XXXXXX", prefix));
+        let template = CString::new(template.into_os_string().into_vec())?;
+        let mut template = template.into_bytes_with_nul();
+        // mkdtemp creates the directory with mode 0700
+        let res = unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) };
+        if res.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        template.pop();
+        Ok(TempDir {
+            path: OsString::from_vec(template).into(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            log::warn!("could not remove {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::filter::Filter;
+#[cfg(target_os = "linux")]
+use crate::signals;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A path the command used, relative to SOURCE.
+pub struct Seen {
+    pub path: PathBuf,
+    /// When the command last saw it as it is, or None if it looked for it
+    /// and found nothing.
+    pub at: Option<SystemTime>,
+}
+
+#[cfg(target_os = "linux")]
+impl Seen {
+    /// Whether the path is no longer what the command saw. Directories are
+    /// left to their watches, since the command's own outputs change them.
+    fn changed(&self, source: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (std::fs::symlink_metadata(source.join(&self.path)), self.at) {
+            (Ok(meta), Some(at)) => {
+                let ctime = Duration::new(meta.ctime() as u64, meta.ctime_nsec() as u32);
+                !meta.is_dir() && SystemTime::UNIX_EPOCH + ctime > at
+            }
+            (Ok(_), None) => true,
+            (Err(_), at) => at.is_some(),
+        }
+    }
+}
+
+/// Blocks until one of `seen` changes, then until `debounce` has passed
+/// without any further change, so a burst of saves triggers a single rerun.
+/// A change made while the command was still running counts straight away.
+/// Changes to paths `filter` leaves out are ignored.
+#[cfg(target_os = "linux")]
+pub fn wait_for_change(
+    source: &Path,
+    seen: &[Seen],
+    filter: &Filter,
+    debounce: Duration,
+) -> io::Result<()> {
+    let mut inotify = Inotify::new()?;
+    for seen in seen {
+        let mut path = seen.path.as_path();
+        // A path that doesn't exist, whether it was never there or has gone
+        // since the run, is watched for through the nearest directory that does
+        loop {
+            match inotify.add(source, path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => match path.parent() {
+                    Some(parent) => path = parent,
+                    None => break,
+                },
+                Err(e) => {
+                    // Most likely the inotify watch limit, which means
+                    // changes will go unnoticed
+                    log::warn!("not watching {}: {}", path.display(), e);
+                    break;
+                }
+                Ok(()) => break,
+            }
+        }
+    }
+    // Checked only now that the watches are in place, so nothing slips
+    // between the check and the wait
+    if !seen.iter().any(|seen| seen.changed(source)) {
+        inotify.wait(filter, None)?;
+    }
+    while inotify.wait(filter, Some(debounce))? {}
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn wait_for_change(
+    _source: &Path,
+    _seen: &[Seen],
+    _filter: &Filter,
+    _debounce: Duration,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "watch mode requires inotify",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: std::os::fd::OwnedFd,
+    /// Watched directories by watch descriptor, relative to SOURCE, for
+    /// telling which path an event in one of them is about.
+    dirs: std::collections::HashMap<libc::c_int, std::path::PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    fn new() -> io::Result<Inotify> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            dirs: std::collections::HashMap::new(),
+        })
+    }
+
+    fn add(&mut self, source: &Path, path: &Path) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let full = source.join(path);
+        let meta = std::fs::symlink_metadata(&full)?;
+        // A directory only matters for its listing, not for its children's contents
+        let mask = if meta.is_dir() {
+            libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO
+        } else {
+            // Not IN_CLOSE_WRITE: it adds nothing over IN_MODIFY, and fires
+            // when the kernel gets round to releasing swatch's own handles
+            libc::IN_MODIFY | libc::IN_ATTRIB
+        };
+        let mask = mask | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_DONT_FOLLOW;
+        let full = CString::new(full.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), full.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if meta.is_dir() {
+            self.dirs.insert(wd, path.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Waits for a change that `filter` keeps, returning false if `timeout`
+    /// passed first. Events about anything else are drained and dropped. A
+    /// caught signal ends the wait with an `Interrupted` error.
+    fn wait(&self, filter: &Filter, timeout: Option<Duration>) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+        use std::time::Instant;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout_ms = match deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(libc::c_int::MAX),
+                None => -1,
+            };
+            let mut pollfds = [self.fd.as_raw_fd(), signals::fd()].map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            let n = unsafe { libc::poll(pollfds.as_mut_ptr(), 2, timeout_ms) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if n == 0 {
+                return Ok(false);
+            }
+            if pollfds[1].revents != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "interrupted by a signal",
+                ));
+            }
+            let mut buf = [0u8; 4096];
+            let res =
+                unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if self.changed(&buf[..res as usize], filter) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Whether any of the events read into `buf` is about a path `filter` keeps.
+    fn changed(&self, mut buf: &[u8], filter: &Filter) -> bool {
+        use std::ffi::OsStr;
+        use std::mem::size_of;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut changed = false;
+        while buf.len() >= size_of::<libc::inotify_event>() {
+            let event: libc::inotify_event =
+                unsafe { buf.as_ptr().cast::<libc::inotify_event>().read_unaligned() };
+            let (name, rest) = buf[size_of::<libc::inotify_event>()..].split_at(event.len as usize);
+            buf = rest;
+            // The name is padded out with NULs
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            changed |= match self.dirs.get(&event.wd) {
+                Some(dir) if !name.is_empty() => {
+                    filter.includes(&dir.join(OsStr::from_bytes(name)))
+                }
+                // An event about a watched path itself, or an overflowed queue
+                _ => true,
+            };
+        }
+        changed
+    }
+}
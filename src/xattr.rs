@@ -0,0 +1,108 @@
+//! Extended attribute calls that don't follow a final symlink, papering over
+//! the differences between the Linux and macOS signatures.
+
+use crate::os::{check, check_len};
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn c_str(s: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(s.as_bytes())?)
+}
+
+/// Reads attribute `name` into `buf`, or with an empty `buf` returns the
+/// size needed to hold it.
+pub fn get(path: &Path, name: &OsStr, buf: &mut [u8]) -> io::Result<usize> {
+    let (path, name) = (c_str(path.as_os_str())?, c_str(name)?);
+    check_len(unsafe { sys::get(&path, &name, buf) })
+}
+
+/// Lists attribute names, NUL-separated, into `buf`, or with an empty `buf`
+/// returns the size needed to hold them.
+pub fn list(path: &Path, buf: &mut [u8]) -> io::Result<usize> {
+    let path = c_str(path.as_os_str())?;
+    check_len(unsafe { sys::list(&path, buf) })
+}
+
+pub fn set(path: &Path, name: &OsStr, value: &[u8], flags: i32) -> io::Result<()> {
+    let (path, name) = (c_str(path.as_os_str())?, c_str(name)?);
+    check(unsafe { sys::set(&path, &name, value, flags) })
+}
+
+pub fn remove(path: &Path, name: &OsStr) -> io::Result<()> {
+    let (path, name) = (c_str(path.as_os_str())?, c_str(name)?);
+    check(unsafe { sys::remove(&path, &name) })
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::CStr;
+
+    pub unsafe fn get(path: &CStr, name: &CStr, buf: &mut [u8]) -> isize {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    }
+
+    pub unsafe fn list(path: &CStr, buf: &mut [u8]) -> isize {
+        libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    }
+
+    pub unsafe fn set(path: &CStr, name: &CStr, value: &[u8], flags: i32) -> libc::c_int {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags,
+        )
+    }
+
+    pub unsafe fn remove(path: &CStr, name: &CStr) -> libc::c_int {
+        libc::lremovexattr(path.as_ptr(), name.as_ptr())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::CStr;
+
+    pub unsafe fn get(path: &CStr, name: &CStr, buf: &mut [u8]) -> isize {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+
+    pub unsafe fn list(path: &CStr, buf: &mut [u8]) -> isize {
+        libc::listxattr(
+            path.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+
+    pub unsafe fn set(path: &CStr, name: &CStr, value: &[u8], flags: i32) -> libc::c_int {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            flags | libc::XATTR_NOFOLLOW,
+        )
+    }
+
+    pub unsafe fn remove(path: &CStr, name: &CStr) -> libc::c_int {
+        libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW)
+    }
+}